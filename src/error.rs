@@ -0,0 +1,54 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+/// Errors raised while turning an EFI header into an `EfiModule`.
+#[derive(Debug)]
+pub enum ParseError {
+    /// libclang could not be loaded or failed to parse the header.
+    Clang(String),
+    /// A declaration uses a type that has no `EfiType` equivalent.
+    UnsupportedType { name: String },
+    /// libclang did not provide a piece of a declaration, e.g. a field's name.
+    MissingField(&'static str),
+    /// The input does not look like an EFI header.
+    Malformed(String),
+    Io(io::Error),
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ParseError::Clang(ref msg) => write!(f, "clang: {}", msg),
+            ParseError::UnsupportedType { ref name } => write!(f, "unsupported type {}", name),
+            ParseError::MissingField(what) => write!(f, "{}", what),
+            ParseError::Malformed(ref msg) => write!(f, "malformed input: {}", msg),
+            ParseError::Io(ref err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl Error for ParseError {
+    fn description(&self) -> &str {
+        match *self {
+            ParseError::Clang(ref msg) => msg,
+            ParseError::UnsupportedType { .. } => "unsupported type",
+            ParseError::MissingField(what) => what,
+            ParseError::Malformed(ref msg) => msg,
+            ParseError::Io(ref err) => err.description(),
+        }
+    }
+
+    fn cause(&self) -> Option<&Error> {
+        match *self {
+            ParseError::Io(ref err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for ParseError {
+    fn from(err: io::Error) -> ParseError {
+        ParseError::Io(err)
+    }
+}
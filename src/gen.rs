@@ -1,5 +1,11 @@
 use types::*;
+use parser::{parse, parse_many_with_options, parse_with_options, ParseOptions};
 use std::io;
+use std::io::prelude::*;
+use std::fs::{self, File};
+use std::path::Path;
+use std::error::Error;
+use std::collections::{HashMap, HashSet};
 use syntax::ast::*;
 use syntax::ast::DUMMY_NODE_ID as DID;
 use syntax::abi::Abi;
@@ -11,8 +17,249 @@ use syntax::print::pp;
 use syntax::ext::base::{ExtCtxt, DummyResolver};
 use syntax::ext::build::AstBuilder;
 use syntax::ext::expand::ExpansionConfig;
-use syntax::parse::ParseSess;
-use syntax::parse::token::{InternedString, fresh_name};
+use syntax::parse::{ParseSess, new_parser_from_source_str};
+use syntax::parse::token::{InternedString, fresh_name, intern_and_get_ident, keywords};
+
+/// Rust spelling of `CHAR8`, `CHAR16` is always emitted as `u16`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum CharMapping {
+    U8,
+    I8,
+    CChar,
+}
+
+/// Rust spelling of `EFI_STATUS`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum StatusStyle {
+    /// The fixed width integer of `UINTN` for `RustOptions::pointer_width`, `usize` if that is
+    /// not known.
+    Uefi,
+    Usize,
+    /// A `Status(usize)` newtype emitted along with the bindings.
+    Newtype,
+}
+
+/// Rust spelling of the pointee of `VOID *`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum VoidPtrStyle {
+    /// `::std::os::raw::c_void`.
+    CVoid,
+    U8,
+}
+
+/// ABI of `EFIAPI` function pointers.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum EfiapiStyle {
+    /// `extern "win64"`, what `efiapi` means on x86_64. The syntax crate predates `efiapi`, so
+    /// it cannot be spelled directly.
+    Win64,
+    /// `extern "C"`, for targets where EFIAPI is the C calling convention.
+    C,
+}
+
+#[derive(Clone, Debug)]
+pub struct RustOptions {
+    pub char_mapping: CharMapping,
+    pub status_as: StatusStyle,
+    pub efiapi_as: EfiapiStyle,
+    pub void_ptr_as: VoidPtrStyle,
+    /// Traits derived on generated records, `Debug` is skipped where it is not implementable.
+    pub derives: Vec<String>,
+    /// Extra attributes such as `#[cfg(feature = "foo")]`, keyed by the EFI name of the item
+    /// they are emitted on.
+    pub item_attrs: HashMap<String, Vec<String>>,
+    /// EFI names of records, enums and callbacks that are referenced but not defined, they are
+    /// assumed to be in scope where the bindings are included.
+    pub type_blocklist: Vec<String>,
+    /// Pointer width in bits of the target, `UINTN` and `INTN` become `usize` and `isize` if it
+    /// is not known.
+    pub pointer_width: Option<u8>,
+    /// Emits slice accessors for array fields of structs, and for `CHAR8` and `CHAR16` arrays
+    /// also one for the string up to the first NUL.
+    pub emit_array_accessors: bool,
+    /// Emits a `PROTOCOLS` table with the name, GUID and method count of every protocol.
+    pub emit_registry: bool,
+    /// Emits a `Protocol` trait with the GUID of each protocol that has one, and a generic
+    /// `locate` if the module defines `EFI_BOOT_SERVICES`.
+    pub emit_protocol_trait: bool,
+}
+
+impl Default for RustOptions {
+    fn default() -> RustOptions {
+        RustOptions {
+            char_mapping: CharMapping::U8,
+            status_as: StatusStyle::Uefi,
+            efiapi_as: EfiapiStyle::Win64,
+            void_ptr_as: VoidPtrStyle::CVoid,
+            derives: vec!["Debug".to_string(), "Clone".to_string(), "Copy".to_string()],
+            item_attrs: HashMap::new(),
+            type_blocklist: Vec::new(),
+            pointer_width: None,
+            emit_array_accessors: false,
+            emit_registry: false,
+            emit_protocol_trait: false,
+        }
+    }
+}
+
+/// Options for going from a header to bindings in one step.
+#[derive(Clone, Debug, Default)]
+pub struct GenOptions {
+    pub parse: ParseOptions,
+    pub rust: RustOptions,
+    /// Fails instead of generating if distinct EFI names map to the same Rust identifier.
+    pub check_collisions: bool,
+}
+
+/// Collects headers and options for generating bindings from a build script, e.g.
+/// `Builder::new().header("Protocol.h").clang_arg("-IInclude").generate()`.
+#[derive(Clone, Debug, Default)]
+pub struct Builder {
+    headers: Vec<String>,
+    options: GenOptions,
+}
+
+impl Builder {
+    pub fn new() -> Builder {
+        Builder::default()
+    }
+
+    /// Adds a header, all headers are parsed as one translation unit.
+    pub fn header<S: Into<String>>(mut self, header: S) -> Builder {
+        self.headers.push(header.into());
+        self
+    }
+
+    /// Adds an argument passed on to clang, e.g. `-I` or `-D` flags.
+    pub fn clang_arg<S: Into<String>>(mut self, arg: S) -> Builder {
+        self.options.parse.clang_args.push(arg.into());
+        self
+    }
+
+    /// Replaces the parse options, including the clang arguments added so far.
+    pub fn parse_options(mut self, options: ParseOptions) -> Builder {
+        self.options.parse = options;
+        self
+    }
+
+    /// Replaces the code generation options.
+    pub fn rust_options(mut self, options: RustOptions) -> Builder {
+        self.options.rust = options;
+        self
+    }
+
+    pub fn efiapi_as(mut self, style: EfiapiStyle) -> Builder {
+        self.options.rust.efiapi_as = style;
+        self
+    }
+
+    pub fn check_collisions(mut self, check: bool) -> Builder {
+        self.options.check_collisions = check;
+        self
+    }
+
+    /// Parses the headers and generates the bindings.
+    pub fn generate(&self) -> Result<Bindings, Box<Error>> {
+        if self.headers.is_empty() {
+            return Err(Box::from("no headers given"));
+        }
+
+        let headers: Vec<&str> = self.headers.iter().map(|h| h.as_str()).collect();
+        let module = try!(parse_many_with_options(&headers, &self.options.parse));
+        if self.options.check_collisions {
+            try!(collisions_to_error(&module));
+        }
+
+        let mut out = Vec::new();
+        try!(gen_module(&module, &self.options.rust, &mut out));
+        Ok(Bindings {
+            module: module,
+            source: try!(String::from_utf8(out)),
+        })
+    }
+}
+
+/// Bindings generated by a `Builder`.
+#[derive(Clone, Debug)]
+pub struct Bindings {
+    module: EfiModule,
+    source: String,
+}
+
+impl Bindings {
+    /// The module the bindings were generated from.
+    pub fn module(&self) -> &EfiModule {
+        &self.module
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.source
+    }
+
+    pub fn into_string(self) -> String {
+        self.source
+    }
+
+    /// Writes the bindings to `out_path` like `write_bindings` does.
+    pub fn write_to_file<P: AsRef<Path>>(&self, out_path: P) -> io::Result<()> {
+        write_if_changed(out_path.as_ref(), self.source.as_bytes())
+    }
+}
+
+/// Module-wide state consulted while generating items.
+struct Env<'a> {
+    module: &'a EfiModule,
+    options: &'a RustOptions,
+    item_attrs: HashMap<&'a str, Vec<Attribute>>,
+    /// Rust identifiers of the items defined by the module, keyed by their EFI name.
+    names: HashMap<&'a str, String>,
+}
+
+impl<'a> Env<'a> {
+    fn is_extern(&self, name: &str) -> bool {
+        self.module.extern_types.contains_key(name)
+    }
+
+    fn rust_name(&self, name: &str) -> String {
+        self.names.get(name).cloned().unwrap_or_else(|| gen_eficc_name(name))
+    }
+
+    fn is_blocklisted(&self, name: &str) -> bool {
+        self.options.type_blocklist.iter().any(|n| n == name)
+    }
+
+    /// The constant the length of array `field` is spelled with and its value, if the module
+    /// defines it as that length.
+    fn len_const(&self, field: &EfiField) -> Option<(&'a str, u64)> {
+        let (name, len) = match (&field.len_const, &field.ty) {
+            (&Some(ref name), &EfiType::Array(_, len)) => (name, len as u64),
+            _ => return None,
+        };
+        self.module
+            .constants
+            .iter()
+            .find(|c| {
+                c.name == *name &&
+                match c.value {
+                    EfiConstValue::Int(value) => value == len,
+                    EfiConstValue::Guid(_) => false,
+                }
+            })
+            .map(|c| (&c.name[..], len))
+    }
+
+    fn with_item_attrs(&self, item: P<Item>, name: &str) -> P<Item> {
+        match self.item_attrs.get(name) {
+            Some(attrs) => {
+                item.map(|mut i| {
+                    i.attrs.extend(attrs.iter().cloned());
+                    i
+                })
+            }
+            None => item,
+        }
+    }
+}
 
 fn gen_cc_name<P: Fn(&str) -> bool>(name: &str, p: P) -> String {
     fn capitalize(s: &str) -> String {
@@ -52,27 +299,72 @@ fn gen_efisc_name(name: &str) -> String {
     sc_name
 }
 
-fn gen_repr_c(cx: &ExtCtxt) -> Attribute {
+fn gen_repr(cx: &ExtCtxt, repr: &'static str) -> Attribute {
     cx.attribute(DSP,
                  cx.meta_list(DSP,
                               InternedString::new("repr"),
-                              vec![cx.meta_list_item_word(DSP, InternedString::new("C"))]))
+                              vec![cx.meta_list_item_word(DSP, InternedString::new(repr))]))
+}
+
+fn gen_repr_c(cx: &ExtCtxt) -> Attribute {
+    gen_repr(cx, "C")
 }
 
-fn gen_derive(cx: &ExtCtxt, traits: &[&'static str]) -> Attribute {
+fn gen_derive<S: AsRef<str>>(cx: &ExtCtxt, traits: &[S]) -> Attribute {
     cx.attribute(DSP,
                  cx.meta_list(DSP,
                               InternedString::new("derive"),
                               traits.iter()
-                                  .map(|t| cx.meta_list_item_word(DSP, InternedString::new(t)))
+                                  .map(|t| intern_and_get_ident(t.as_ref()))
+                                  .map(|t| cx.meta_list_item_word(DSP, t))
                                   .collect()))
 }
 
-fn gen_type(cx: &ExtCtxt, ty: &EfiType, dir: Option<EfiArgDir>) -> P<Ty> {
+fn gen_attr(cx: &ExtCtxt, attr: &str) -> io::Result<Attribute> {
+    let mut parser = new_parser_from_source_str(cx.parse_sess,
+                                                cx.cfg.clone(),
+                                                String::from("<item_attrs>"),
+                                                String::from(attr));
+    parser.parse_attribute(false).map_err(|mut e| {
+        e.cancel();
+        io::Error::new(io::ErrorKind::InvalidInput, format!("invalid attribute {}", attr))
+    })
+}
+
+fn gen_doc(cx: &ExtCtxt, doc: &str) -> Attribute {
+    let doc = intern_and_get_ident(doc);
+    cx.attribute(DSP,
+                 cx.meta_name_value(DSP,
+                                    InternedString::new("doc"),
+                                    LitKind::Str(doc, StrStyle::Cooked)))
+}
+
+/// Doc comment naming the C declaration an item is generated from.
+fn gen_c_name(cx: &ExtCtxt, name: &str) -> Attribute {
+    gen_doc(cx, &format!("`{}`", name))
+}
+
+fn gen_deprecated(cx: &ExtCtxt) -> Attribute {
+    cx.attribute(DSP, cx.meta_word(DSP, InternedString::new("deprecated")))
+}
+
+fn gen_type(cx: &ExtCtxt, env: &Env, ty: &EfiType) -> P<Ty> {
     match *ty {
-        EfiType::Status => cx.ty_ident(DSP, cx.ident_of("u64")),
-        EfiType::UIntN => cx.ty_ident(DSP, cx.ident_of("u64")),
-        EfiType::IntN => cx.ty_ident(DSP, cx.ident_of("i64")),
+        EfiType::Void => cx.ty(DSP, TyKind::Tup(vec![])),
+        EfiType::Status => {
+            match env.options.status_as {
+                StatusStyle::Uefi => gen_type(cx, env, &EfiType::UIntN),
+                StatusStyle::Usize => cx.ty_ident(DSP, cx.ident_of("usize")),
+                StatusStyle::Newtype => cx.ty_ident(DSP, cx.ident_of("Status")),
+            }
+        }
+        EfiType::UIntN | EfiType::IntN => {
+            match env.options.pointer_width.map(|w| ty.concrete_width(w)) {
+                Some(ref concrete) if concrete != ty => gen_type(cx, env, concrete),
+                _ if *ty == EfiType::UIntN => cx.ty_ident(DSP, cx.ident_of("usize")),
+                _ => cx.ty_ident(DSP, cx.ident_of("isize")),
+            }
+        }
         EfiType::Bool => cx.ty_ident(DSP, cx.ident_of("bool")),
         EfiType::Int8 => cx.ty_ident(DSP, cx.ident_of("i8")),
         EfiType::UInt8 => cx.ty_ident(DSP, cx.ident_of("u8")),
@@ -82,45 +374,351 @@ fn gen_type(cx: &ExtCtxt, ty: &EfiType, dir: Option<EfiArgDir>) -> P<Ty> {
         EfiType::UInt32 => cx.ty_ident(DSP, cx.ident_of("u32")),
         EfiType::Int64 => cx.ty_ident(DSP, cx.ident_of("i64")),
         EfiType::UInt64 => cx.ty_ident(DSP, cx.ident_of("u64")),
-        EfiType::Char8 => cx.ty_ident(DSP, cx.ident_of("u8")),
+        EfiType::Char8 => {
+            match env.options.char_mapping {
+                CharMapping::U8 => cx.ty_ident(DSP, cx.ident_of("u8")),
+                CharMapping::I8 => cx.ty_ident(DSP, cx.ident_of("i8")),
+                CharMapping::CChar => {
+                    let path = ["std", "os", "raw", "c_char"].iter().map(|s| cx.ident_of(s));
+                    cx.ty_path(cx.path_global(DSP, path.collect()))
+                }
+            }
+        }
         EfiType::Char16 => cx.ty_ident(DSP, cx.ident_of("u16")),
-        EfiType::Id(ref name) => cx.ty_ident(DSP, cx.ident_of(&gen_eficc_name(&name))),
-        EfiType::Ptr(ref ty) => {
-            let mutbl = match dir {
-                Some(EfiArgDir::In) => Mutability::Immutable,
-                Some(EfiArgDir::Out) |
-                None => Mutability::Mutable,
-            };
-            cx.ty_ptr(DSP, gen_type(cx, &*ty, dir), mutbl)
+        EfiType::Float32 => cx.ty_ident(DSP, cx.ident_of("f32")),
+        EfiType::Float64 => cx.ty_ident(DSP, cx.ident_of("f64")),
+        EfiType::Ucs2Ptr => gen_type(cx, env, &EfiType::Ptr(Box::new(EfiType::Char16))),
+        EfiType::StringId => cx.ty_ident(DSP, cx.ident_of("u16")),
+        EfiType::Guid => cx.ty_ident(DSP, cx.ident_of("Guid")),
+        EfiType::Id(ref name) => {
+            match env.module.extern_types.get(name) {
+                Some(path) => {
+                    let idents = path.split("::")
+                        .filter(|s| !s.is_empty())
+                        .map(|s| cx.ident_of(s))
+                        .collect();
+                    if path.starts_with("::") {
+                        cx.ty_path(cx.path_global(DSP, idents))
+                    } else {
+                        cx.ty_path(cx.path(DSP, idents))
+                    }
+                }
+                None => cx.ty_ident(DSP, cx.ident_of(&env.rust_name(name))),
+            }
+        }
+        EfiType::Array(ref ty, len) => {
+            let len = cx.expr_lit(DSP, LitKind::Int(len as u64, LitIntType::Unsuffixed));
+            cx.ty(DSP, TyKind::FixedLengthVec(gen_type(cx, env, ty), len))
+        }
+        // `IN` doesn't make the pointee const, the callee may still write through it
+        EfiType::Ptr(ref ty) => cx.ty_ptr(DSP, gen_pointee(cx, env, ty), Mutability::Mutable),
+        EfiType::ConstPtr(ref ty) => {
+            cx.ty_ptr(DSP, gen_pointee(cx, env, ty), Mutability::Immutable)
+        }
+    }
+}
+
+/// `void` behind a pointer is `c_void` or `u8` rather than `()`.
+fn gen_pointee(cx: &ExtCtxt, env: &Env, ty: &EfiType) -> P<Ty> {
+    match *ty {
+        EfiType::Void => {
+            match env.options.void_ptr_as {
+                VoidPtrStyle::CVoid => {
+                    let path = ["std", "os", "raw", "c_void"].iter().map(|s| cx.ident_of(s));
+                    cx.ty_path(cx.path_global(DSP, path.collect()))
+                }
+                VoidPtrStyle::U8 => cx.ty_ident(DSP, cx.ident_of("u8")),
+            }
         }
+        ref ty => gen_type(cx, env, ty),
     }
 }
 
-fn gen_field(cx: &ExtCtxt, field: &EfiField) -> StructField {
+fn gen_field(cx: &ExtCtxt, env: &Env, field: &EfiField) -> StructField {
+    // reserved fields are kept for the layout only
+    let (name, vis) = if field.is_reserved {
+        (format!("_{}", gen_efisc_name(&field.name)), Visibility::Inherited)
+    } else {
+        (gen_efisc_name(&field.name), Visibility::Public)
+    };
+
+    // the length stays tied to the constant it is spelled with
+    let ty = match (&field.ty, env.len_const(field)) {
+        (&EfiType::Array(ref ty, _), Some((name, _))) => {
+            let path = cx.path(DSP, vec![cx.ident_of("consts"), cx.ident_of(name)]);
+            let len = cx.expr_cast(DSP, cx.expr_path(path), cx.ty_ident(DSP, cx.ident_of("usize")));
+            cx.ty(DSP, TyKind::FixedLengthVec(gen_type(cx, env, ty), len))
+        }
+        (ty, _) => gen_type(cx, env, ty),
+    };
+
     StructField {
         span: DSP,
-        ident: Some(cx.ident_of(&gen_efisc_name(&field.name))),
-        vis: Visibility::Public,
+        ident: Some(cx.ident_of(&name)),
+        vis: vis,
         id: DID,
-        ty: gen_type(cx, &field.ty, None),
+        ty: ty,
         attrs: Vec::new(),
     }
 }
 
-fn gen_record(cx: &ExtCtxt, record: &EfiRecord) -> P<Item> {
-    let fields = VariantData::Struct(record.fields
+/// A `consts` module with the constants array lengths are spelled with.
+fn gen_consts(cx: &ExtCtxt, env: &Env) -> Option<P<Item>> {
+    let records = env.module
+        .records
+        .iter()
+        .filter(|r| !env.is_extern(&r.name) && !env.is_blocklisted(&r.name))
+        .flat_map(|r| r.fields.iter());
+    let protocols = env.module
+        .protocols
+        .iter()
+        .filter(|p| !env.is_extern(&p.name))
+        .flat_map(|p| p.fields.iter());
+    let mut consts: Vec<_> = records.chain(protocols).filter_map(|f| env.len_const(f)).collect();
+    if consts.is_empty() {
+        return None;
+    }
+    consts.sort();
+    consts.dedup();
+
+    let items = consts.iter()
+        .map(|&(name, value)| {
+            let value = cx.expr_lit(DSP, LitKind::Int(value, LitIntType::Unsuffixed));
+            cx.item_const(DSP, cx.ident_of(name), cx.ty_ident(DSP, cx.ident_of("u64")), value)
+                .map(|mut c| {
+                    c.vis = Visibility::Public;
+                    c
+                })
+        })
+        .collect();
+
+    Some(cx.item_mod(DSP, DSP, cx.ident_of("consts"), vec![], items).map(|mut m| {
+        m.vis = Visibility::Public;
+        m
+    }))
+}
+
+/// Whether `Debug` can be derived for a record, neither unions nor arrays of more than 32
+/// elements implement it. Types provided from outside are not trusted to.
+fn is_debug_record(env: &Env, record: &EfiRecord) -> bool {
+    fn is_debug(env: &Env, ty: &EfiType) -> bool {
+        match *ty {
+            EfiType::Array(ref ty, len) => len <= 32 && is_debug(env, ty),
+            EfiType::Id(ref name) => {
+                if let Some(record) = env.module.records.iter().find(|r| r.name == *name) {
+                    return is_debug_record(env, record);
+                }
+                if let Some(alias) = env.module.aliases.iter().find(|a| a.0 == *name) {
+                    return is_debug(env, &alias.1);
+                }
+                // callbacks are function pointers
+                env.module.enums.iter().any(|e| e.name == *name) ||
+                env.module.callbacks.iter().any(|c| c.name == *name)
+            }
+            _ => true,
+        }
+    }
+
+    record.kind == EfiRecordKind::Struct && !env.is_extern(&record.name) &&
+    record.fields.iter().all(|f| is_debug(env, &f.ty))
+}
+
+/// Whether `Copy` and `Clone` can be derived for a record. A record ending in a flexible array
+/// is only the header of a larger object, copying it would cut off the trailing elements.
+fn is_copy_record(env: &Env, record: &EfiRecord) -> bool {
+    fn is_copy(env: &Env, ty: &EfiType) -> bool {
+        match *ty {
+            EfiType::Array(_, 0) => false,
+            EfiType::Array(ref ty, _) => is_copy(env, ty),
+            EfiType::Id(ref name) => {
+                if let Some(record) = env.module.records.iter().find(|r| r.name == *name) {
+                    return is_copy_record(env, record);
+                }
+                if let Some(alias) = env.module.aliases.iter().find(|a| a.0 == *name) {
+                    return is_copy(env, &alias.1);
+                }
+                // callbacks are function pointers
+                env.module.enums.iter().any(|e| e.name == *name) ||
+                env.module.callbacks.iter().any(|c| c.name == *name)
+            }
+            _ => true,
+        }
+    }
+
+    !env.is_extern(&record.name) && record.fields.iter().all(|f| is_copy(env, &f.ty))
+}
+
+/// Bitfields sharing a storage unit, which is emitted as one field of their type.
+struct BitfieldUnit<'a> {
+    name: String,
+    ty: &'a EfiType,
+    /// The bitfields with their first bit.
+    members: Vec<(&'a EfiField, u32)>,
+    used: u32,
+}
+
+enum Member<'a> {
+    Field(&'a EfiField),
+    Bitfields(BitfieldUnit<'a>),
+}
+
+/// The fields of the generated struct. Consecutive bitfields of one type share a unit while
+/// they fit, which is what both the MS and the SysV rules do for them.
+///
+/// Fails if the units do not reproduce the C layout, e.g. for SysV bitfields that continue in
+/// the storage of a preceding field of another type.
+fn gen_members<'a>(env: &Env, record: &'a EfiRecord) -> io::Result<Vec<Member<'a>>> {
+    // every member of a union starts at 0, so bitfields take up all of their type there
+    if record.kind == EfiRecordKind::Union {
+        return Ok(record.fields.iter().map(Member::Field).collect());
+    }
+
+    let pointer_widths = match env.options.pointer_width {
+        Some(width) => vec![width],
+        None => vec![32, 64],
+    };
+    let unit_bits = |ty: &EfiType| {
+        ty.layout(env.module, pointer_widths[0]).map_or(0, |(size, _)| size as u32 * 8)
+    };
+
+    let mut members: Vec<Member> = Vec::new();
+    let mut units = 0;
+    // a zero width bitfield closes the unit
+    let mut open = false;
+    for field in &record.fields {
+        let width = match field.bit_width {
+            Some(width) => width,
+            None => {
+                members.push(Member::Field(field));
+                open = false;
+                continue;
+            }
+        };
+        if width == 0 {
+            open = false;
+            continue;
+        }
+
+        if let Some(&mut Member::Bitfields(ref mut unit)) = members.last_mut() {
+            if open && unit.ty == &field.ty && unit.used + width <= unit_bits(&field.ty) {
+                unit.members.push((field, unit.used));
+                unit.used += width;
+                continue;
+            }
+        }
+
+        units += 1;
+        members.push(Member::Bitfields(BitfieldUnit {
+            name: format!("bitfield_{}", units),
+            ty: &field.ty,
+            members: vec![(field, 0)],
+            used: width,
+        }));
+        open = true;
+    }
+
+    if units == 0 {
+        return Ok(members);
+    }
+
+    // the units are plain fields to Rust, compare that layout with the C one
+    let plain = EfiRecord {
+        fields: members.iter()
+            .map(|m| match *m {
+                Member::Field(field) => field.clone(),
+                Member::Bitfields(ref unit) => {
+                    EfiField {
+                        name: unit.name.clone(),
+                        ty: unit.ty.clone(),
+                        is_reserved: false,
+                        bit_width: None,
+                        len_const: None,
+                        doc: None,
+                    }
+                }
+            })
+            .collect(),
+        ..record.clone()
+    };
+    for &width in &pointer_widths {
+        let offsets = |r: &EfiRecord| {
+            r.field_offsets(env.module, width)
+                .into_iter()
+                .filter(|&(ref name, _)| {
+                    record.fields.iter().any(|f| f.name == *name && f.bit_width.is_none())
+                })
+                .collect::<Vec<_>>()
+        };
+        let c_layout = record.layout(env.module, width);
+        let rust_layout = plain.layout(env.module, width);
+        if c_layout.is_some() && rust_layout.is_some() &&
+           (c_layout != rust_layout || offsets(record) != offsets(&plain)) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      format!("bitfields of {} cannot be laid out as in C",
+                                              record.name)));
+        }
+    }
+
+    Ok(members)
+}
+
+fn gen_bitfield_unit(cx: &ExtCtxt, env: &Env, unit: &BitfieldUnit) -> StructField {
+    let bits = unit.members
+        .iter()
+        .filter(|&&(field, _)| !field.is_reserved)
+        .map(|&(field, start)| match field.bit_width {
+            Some(1) => format!("`{}`: bit {}", field.name, start),
+            Some(width) => format!("`{}`: bits {}-{}", field.name, start, start + width - 1),
+            None => unreachable!(),
+        })
+        .collect::<Vec<_>>();
+
+    StructField {
+        span: DSP,
+        ident: Some(cx.ident_of(&unit.name)),
+        vis: Visibility::Public,
+        id: DID,
+        ty: gen_type(cx, env, unit.ty),
+        attrs: vec![if bits.is_empty() {
+                        gen_doc(cx, "Padding")
+                    } else {
+                        gen_doc(cx, &format!("Bitfields {}", bits.join(", ")))
+                    }],
+    }
+}
+
+fn gen_record(cx: &ExtCtxt, env: &Env, record: &EfiRecord) -> io::Result<P<Item>> {
+    let fields = VariantData::Struct(try!(gen_members(env, record))
                                          .iter()
-                                         .map(|f| gen_field(cx, f))
+                                         .map(|m| match *m {
+                                             Member::Field(f) => gen_field(cx, env, f),
+                                             Member::Bitfields(ref unit) => {
+                                                 gen_bitfield_unit(cx, env, unit)
+                                             }
+                                         })
                                          .collect(),
                                      DID);
 
-    P(Item {
-        ident: cx.ident_of(&gen_eficc_name(&record.name)),
-        attrs: vec![gen_repr_c(cx),
-                    match record.kind {
-                        EfiRecordKind::Struct => gen_derive(cx, &["Copy", "Clone", "Debug"]),
-                        EfiRecordKind::Union => gen_derive(cx, &["Copy", "Clone"]),
-                    }],
+    let debug = is_debug_record(env, record);
+    let copy = is_copy_record(env, record);
+    let derives = env.options
+        .derives
+        .iter()
+        .filter(|d| match d.as_str() {
+            "Debug" => debug,
+            "Clone" | "Copy" => copy,
+            _ => true,
+        })
+        .collect::<Vec<_>>();
+
+    let mut attrs = vec![gen_c_name(cx, &record.name), gen_repr_c(cx)];
+    if !derives.is_empty() {
+        attrs.push(gen_derive(cx, &derives));
+    }
+
+    Ok(P(Item {
+        ident: cx.ident_of(&env.rust_name(&record.name)),
+        attrs: attrs,
         id: DID,
         node: match record.kind {
             EfiRecordKind::Struct => ItemKind::Struct(fields, Generics::default()),
@@ -128,116 +726,453 @@ fn gen_record(cx: &ExtCtxt, record: &EfiRecord) -> P<Item> {
         },
         vis: Visibility::Public,
         span: DSP,
-    })
+    }))
+}
+
+fn gen_array_accessors(cx: &ExtCtxt, env: &Env, record: &EfiRecord) -> Option<P<Item>> {
+    // union fields cannot be read safely
+    if record.kind != EfiRecordKind::Struct {
+        return None;
+    }
+
+    let method = |name: String, elem: P<Ty>, body: P<Expr>| {
+        let this = Arg::from_self(dummy_spanned(SelfKind::Region(None, Mutability::Immutable)),
+                                  dummy_spanned(keywords::SelfValue.ident()));
+        let ty = cx.ty_rptr(DSP, cx.ty(DSP, TyKind::Vec(elem)), None, Mutability::Immutable);
+        ImplItem {
+            id: DID,
+            ident: cx.ident_of(&name),
+            vis: Visibility::Public,
+            defaultness: Defaultness::Final,
+            attrs: vec![],
+            node: ImplItemKind::Method(MethodSig {
+                                           unsafety: Unsafety::Normal,
+                                           constness: dummy_spanned(Constness::NotConst),
+                                           abi: Abi::Rust,
+                                           decl: cx.fn_decl(vec![this], ty),
+                                           generics: Generics::default(),
+                                       },
+                                       cx.block_expr(body)),
+            span: DSP,
+        }
+    };
+
+    let mut methods = Vec::new();
+    for field in record.fields.iter().filter(|f| !f.is_reserved) {
+        let elem = match field.ty {
+            // a flexible array member has no elements of its own
+            EfiType::Array(_, 0) => continue,
+            EfiType::Array(ref elem, _) => elem,
+            _ => continue,
+        };
+        let name = gen_efisc_name(&field.name);
+        let array = || cx.expr_field_access(DSP, cx.expr_self(DSP), cx.ident_of(&name));
+
+        methods.push(method(format!("{}_slice", name),
+                            gen_type(cx, env, elem),
+                            cx.expr_addr_of(DSP, array())));
+        if **elem == EfiType::Char8 || **elem == EfiType::Char16 {
+            // up to the first NUL, or all of it
+            let c = cx.ident_of("c");
+            let is_nul = cx.expr_binary(DSP,
+                                        BinOpKind::Eq,
+                                        cx.expr_deref(DSP, cx.expr_ident(DSP, c)),
+                                        cx.expr_lit(DSP,
+                                                    LitKind::Int(0, LitIntType::Unsuffixed)));
+            let decl = P(FnDecl {
+                inputs: vec![cx.arg(DSP, c, cx.ty_infer(DSP))],
+                output: FunctionRetTy::Default(DSP),
+                variadic: false,
+            });
+            let is_nul = cx.lambda_fn_decl(DSP, decl, cx.block_expr(is_nul), DSP);
+            let position = cx.expr_method_call(DSP,
+                                               cx.expr_method_call(DSP,
+                                                                   array(),
+                                                                   cx.ident_of("iter"),
+                                                                   vec![]),
+                                               cx.ident_of("position"),
+                                               vec![is_nul]);
+            let len = cx.expr_method_call(DSP,
+                                          position,
+                                          cx.ident_of("unwrap_or"),
+                                          vec![cx.expr_method_call(DSP,
+                                                                   array(),
+                                                                   cx.ident_of("len"),
+                                                                   vec![])]);
+            let range = cx.expr(DSP, ExprKind::Range(None, Some(len), RangeLimits::HalfOpen));
+            let index = cx.expr(DSP, ExprKind::Index(array(), range));
+            methods.push(method(format!("{}_str", name),
+                                gen_type(cx, env, elem),
+                                cx.expr_addr_of(DSP, index)));
+        }
+    }
+
+    if methods.is_empty() {
+        return None;
+    }
+
+    let ty = cx.ty_ident(DSP, cx.ident_of(&env.rust_name(&record.name)));
+    let imp = ItemKind::Impl(Unsafety::Normal,
+                             ImplPolarity::Positive,
+                             Generics::default(),
+                             None,
+                             ty,
+                             methods);
+    Some(cx.item(DSP, keywords::Invalid.ident(), vec![], imp))
 }
 
-fn gen_enum(cx: &ExtCtxt, enm: &EfiEnum) -> P<Item> {
+fn gen_status(cx: &ExtCtxt) -> P<Item> {
+    let field = StructField {
+        span: DSP,
+        ident: None,
+        vis: Visibility::Public,
+        id: DID,
+        ty: cx.ty_ident(DSP, cx.ident_of("usize")),
+        attrs: Vec::new(),
+    };
+
+    cx.item_struct(DSP, cx.ident_of("Status"), VariantData::Tuple(vec![field], DID))
+        .map(|mut s| {
+            s.attrs = vec![gen_repr(cx, "transparent"),
+                           gen_derive(cx, &["Copy", "Clone", "Debug", "PartialEq", "Eq"])];
+            s.vis = Visibility::Public;
+            s
+        })
+}
+
+fn gen_guid(cx: &ExtCtxt) -> P<Item> {
+    let field = |name, ty| {
+        StructField {
+            span: DSP,
+            ident: Some(cx.ident_of(name)),
+            vis: Visibility::Public,
+            id: DID,
+            ty: ty,
+            attrs: Vec::new(),
+        }
+    };
+
+    let len = cx.expr_lit(DSP, LitKind::Int(8, LitIntType::Unsuffixed));
+    let data4 = cx.ty(DSP, TyKind::FixedLengthVec(cx.ty_ident(DSP, cx.ident_of("u8")), len));
+    let fields = vec![field("data1", cx.ty_ident(DSP, cx.ident_of("u32"))),
+                      field("data2", cx.ty_ident(DSP, cx.ident_of("u16"))),
+                      field("data3", cx.ty_ident(DSP, cx.ident_of("u16"))),
+                      field("data4", data4)];
+
+    cx.item_struct(DSP, cx.ident_of("Guid"), VariantData::Struct(fields, DID))
+        .map(|mut s| {
+            s.attrs = vec![gen_repr_c(cx),
+                           gen_derive(cx, &["Copy", "Clone", "Debug", "PartialEq", "Eq"])];
+            s.vis = Visibility::Public;
+            s
+        })
+}
+
+fn gen_protocol_info(cx: &ExtCtxt) -> P<Item> {
+    let field = |name, ty| {
+        StructField {
+            span: DSP,
+            ident: Some(cx.ident_of(name)),
+            vis: Visibility::Public,
+            id: DID,
+            ty: ty,
+            attrs: Vec::new(),
+        }
+    };
+
+    let lifetime = cx.lifetime(DSP, keywords::StaticLifetime.name());
+    let name = cx.ty_rptr(DSP,
+                          cx.ty_ident(DSP, cx.ident_of("str")),
+                          Some(lifetime),
+                          Mutability::Immutable);
+    let len = cx.expr_lit(DSP, LitKind::Int(16, LitIntType::Unsuffixed));
+    let guid = cx.ty(DSP, TyKind::FixedLengthVec(cx.ty_ident(DSP, cx.ident_of("u8")), len));
+    let fields = vec![field("name", name),
+                      field("guid", cx.ty_option(guid)),
+                      field("methods", cx.ty_ident(DSP, cx.ident_of("usize")))];
+
+    cx.item_struct(DSP, cx.ident_of("ProtocolInfo"), VariantData::Struct(fields, DID))
+        .map(|mut s| {
+            s.attrs = vec![gen_derive(cx, &["Copy", "Clone", "Debug"])];
+            s.vis = Visibility::Public;
+            s
+        })
+}
+
+fn gen_registry(cx: &ExtCtxt, protocols: &[&EfiProtocol]) -> P<Item> {
     let gen_lit = |v| cx.expr_lit(DSP, LitKind::Int(v, LitIntType::Unsuffixed));
 
+    let entries = protocols.iter()
+        .map(|p| {
+            let guid = match p.guid {
+                Some(ref guid) => {
+                    let bytes = guid.to_bytes().iter().map(|&b| gen_lit(b as u64)).collect();
+                    cx.expr_some(DSP, cx.expr_vec(DSP, bytes))
+                }
+                None => cx.expr_none(DSP),
+            };
+            let fields = vec![cx.field_imm(DSP,
+                                           cx.ident_of("name"),
+                                           cx.expr_str(DSP, intern_and_get_ident(&p.name))),
+                              cx.field_imm(DSP, cx.ident_of("guid"), guid),
+                              cx.field_imm(DSP,
+                                           cx.ident_of("methods"),
+                                           gen_lit(p.methods.len() as u64))];
+            cx.expr_struct_ident(DSP, cx.ident_of("ProtocolInfo"), fields)
+        })
+        .collect();
+
+    let lifetime = cx.lifetime(DSP, keywords::StaticLifetime.name());
+    let ty = cx.ty_rptr(DSP,
+                        cx.ty(DSP, TyKind::Vec(cx.ty_ident(DSP, cx.ident_of("ProtocolInfo")))),
+                        Some(lifetime),
+                        Mutability::Immutable);
+
+    cx.item_const(DSP, cx.ident_of("PROTOCOLS"), ty, cx.expr_vec_slice(DSP, entries))
+        .map(|mut c| {
+            c.vis = Visibility::Public;
+            c
+        })
+}
+
+fn gen_enum(cx: &ExtCtxt, env: &Env, enm: &EfiEnum) -> P<Item> {
+    let gen_lit = |v| cx.expr_lit(DSP, LitKind::Int(v, LitIntType::Unsuffixed));
+    let gen_value = |v: u64| if enm.signed && (v as i64) < 0 {
+        cx.expr_unary(DSP, UnOp::Neg, gen_lit((v as i64).wrapping_neg() as u64))
+    } else {
+        gen_lit(v)
+    };
+
     let vs = enm.fields
         .iter()
         .map(|f| {
             let mut v = cx.variant(DSP, cx.ident_of(&f.name), vec![]);
-            v.node.disr_expr = f.value.map(&gen_lit);
+            v.node.disr_expr = f.value.map(&gen_value);
             v
         })
         .collect();
 
-    cx.item_enum(DSP, cx.ident_of(&enm.name), EnumDef { variants: vs })
+    // `repr(C)` discriminants are `isize`, which a 32-bit target can't fit every `UINT32` in;
+    // C itself widens the enum past `int` for such values
+    let values = enm.fields.iter().filter_map(|f| f.value);
+    let repr = if enm.signed {
+        let values: Vec<_> = values.map(|v| v as i64).collect();
+        if values.iter().all(|&v| v >= i32::min_value() as i64 && v <= i32::max_value() as i64) {
+            "C"
+        } else {
+            "i64"
+        }
+    } else {
+        match values.max() {
+            Some(max) if max > u32::max_value() as u64 => "u64",
+            Some(max) if max > i32::max_value() as u64 => "u32",
+            _ => "C",
+        }
+    };
+
+    cx.item_enum(DSP, cx.ident_of(&env.rust_name(&enm.name)), EnumDef { variants: vs })
         .map(|mut e| {
-            e.attrs = vec![gen_repr_c(cx), gen_derive(cx, &["Copy", "Clone", "Debug"])];
+            e.attrs = vec![gen_c_name(cx, &enm.name),
+                           gen_repr(cx, repr),
+                           gen_derive(cx, &["Copy", "Clone", "Debug"])];
             e.vis = Visibility::Public;
             e
         })
 }
 
-fn gen_bare_method(cx: &ExtCtxt, method: &EfiMethod) -> StructField {
+fn gen_bare_fn(cx: &ExtCtxt, env: &Env, method: &EfiMethod) -> P<Ty> {
     let args = method.args
         .iter()
         .map(|arg| {
             cx.arg(DSP,
                    cx.ident_of(&gen_efisc_name(&arg.name)),
-                   gen_type(cx, &arg.ty, Some(arg.dir)))
+                   gen_type(cx, env, &arg.ty))
         })
         .collect();
 
-    let ty = Ty {
+    P(Ty {
         id: DID,
         node: TyKind::BareFn(P(BareFnTy {
             unsafety: Unsafety::Unsafe,
-            abi: Abi::Win64,
+            abi: match method.abi {
+                EfiAbi::Efiapi if env.options.efiapi_as == EfiapiStyle::Win64 => Abi::Win64,
+                EfiAbi::Efiapi | EfiAbi::C => Abi::C,
+            },
             lifetimes: vec![],
-            decl: cx.fn_decl(args, gen_type(cx, &method.ty, None)),
+            decl: cx.fn_decl(args, gen_type(cx, env, &method.ty)),
         })),
         span: DSP,
+    })
+}
+
+fn gen_bare_method(cx: &ExtCtxt, env: &Env, method: &EfiMethod) -> StructField {
+    // methods declared with a shared typedef reference its alias
+    let ty = match method.typedef {
+        Some(ref name) if env.module.callbacks.iter().any(|c| c.name == *name) => {
+            gen_type(cx, env, &EfiType::Id(name.clone()))
+        }
+        _ => gen_bare_fn(cx, env, method),
     };
 
+    // firmware leaves unimplemented methods NULL
     StructField {
         span: DSP,
         ident: Some(cx.ident_of(&gen_efisc_name(&method.name))),
         vis: Visibility::Public,
         id: DID,
-        ty: P(ty),
+        ty: cx.ty_option(ty),
         attrs: Vec::new(),
     }
 }
 
+fn gen_callback(cx: &ExtCtxt, env: &Env, callback: &EfiMethod) -> P<Item> {
+    cx.item_ty(DSP,
+                 cx.ident_of(&env.rust_name(&callback.name)),
+                 gen_bare_fn(cx, env, callback))
+        .map(|mut t| {
+            t.attrs.push(gen_c_name(cx, &callback.name));
+            if callback.deprecated {
+                t.attrs.push(gen_deprecated(cx));
+            }
+            t.vis = Visibility::Public;
+            t
+        })
+}
+
+fn gen_alias(cx: &ExtCtxt, env: &Env, name: &str, ty: &EfiType) -> P<Item> {
+    cx.item_ty(DSP, cx.ident_of(&env.rust_name(name)), gen_type(cx, env, ty))
+        .map(|mut t| {
+            t.attrs.push(gen_c_name(cx, name));
+            t.vis = Visibility::Public;
+            t
+        })
+}
+
 // FIXME: method/field order
-fn gen_bare_protocol(cx: &ExtCtxt, proto: &EfiProtocol) -> P<Item> {
-    let methods = proto.methods.iter().map(|m| gen_bare_method(cx, m));
-    let fields = proto.fields.iter().map(|f| gen_field(cx, f));
+fn gen_bare_protocol(cx: &ExtCtxt, env: &Env, proto: &EfiProtocol) -> P<Item> {
+    let methods = proto.methods.iter().map(|m| gen_bare_method(cx, env, m));
+    let fields = proto.fields.iter().map(|f| gen_field(cx, env, f));
     let entries = methods.chain(fields).map(|mut f| {
         f.vis = Visibility::Inherited;
         f
     });
 
     cx.item_struct(DSP,
-                     cx.ident_of(&gen_eficc_name(&proto.name)),
+                     cx.ident_of(&env.rust_name(&proto.name)),
                      VariantData::Struct(entries.collect(), DID))
         .map(|mut s| {
-            s.attrs = vec![gen_repr_c(cx)];
+            s.attrs = vec![gen_c_name(cx, &proto.name), gen_repr_c(cx)];
+            if proto.deprecated {
+                s.attrs.push(gen_deprecated(cx));
+            }
             s.vis = Visibility::Public;
             s
         })
 }
 
-fn gen_method_sig(cx: &ExtCtxt, method: &EfiMethod) -> MethodSig {
+/// Pointee of an out parameter that the trait method hands back in its `Ok` value instead of
+/// taking it as an argument. Buffers behind `void` pointers stay parameters, as do buffers
+/// whose length another argument holds, which may count bytes or elements.
+fn returned_pointee<'a>(method: &EfiMethod, arg: &'a EfiArg) -> Option<&'a EfiType> {
+    if method.ty != EfiType::Status || arg.dir != EfiArgDir::Out || arg.optional {
+        return None;
+    }
+    let sized = method.args
+        .iter()
+        .filter_map(|a| a.length_of)
+        .any(|i| &method.args[i] as *const EfiArg == arg as *const EfiArg);
+    if sized {
+        return None;
+    }
+
+    match arg.written_type() {
+        Some(ty) if *ty != EfiType::Void => Some(ty),
+        _ => None,
+    }
+}
+
+fn is_pointer(ty: &EfiType) -> bool {
+    match *ty {
+        EfiType::Ptr(_) |
+        EfiType::ConstPtr(_) |
+        EfiType::Ucs2Ptr => true,
+        _ => false,
+    }
+}
+
+/// Whether `arg`, the `i`th argument of a protocol method, is the `This` pointer. Goes by
+/// position rather than name, which `ParseOptions::arg_name_fn` may have changed.
+fn is_receiver(i: usize, arg: &EfiArg) -> bool {
+    match arg.ty {
+        EfiType::Ptr(_) | EfiType::ConstPtr(_) => i == 0 && arg.dir == EfiArgDir::In,
+        _ => false,
+    }
+}
+
+fn gen_method_sig(cx: &ExtCtxt, env: &Env, method: &EfiMethod) -> MethodSig {
     let args = method.args
         .iter()
-        .map(|a| {
-            if a.name == "This" {
+        .enumerate()
+        .filter(|&(_, a)| returned_pointee(method, a).is_none())
+        .map(|(i, a)| {
+            if is_receiver(i, a) {
                 cx.arg(DSP, cx.ident_of("self"), cx.ty(DSP, TyKind::ImplicitSelf))
 
             } else {
+                let ty = gen_type(cx, env, &a.ty);
                 cx.arg(DSP,
                        cx.ident_of(&gen_efisc_name(&a.name)),
-                       gen_type(cx, &a.ty, Some(a.dir)))
+                       if a.optional && is_pointer(&a.ty) { cx.ty_option(ty) } else { ty })
             }
         })
         .collect();
 
+    let ret = if method.ty == EfiType::Status {
+        let mut values: Vec<_> = method.args
+            .iter()
+            .filter_map(|a| returned_pointee(method, a))
+            .map(|ty| gen_type(cx, env, ty))
+            .collect();
+        let value = if values.len() == 1 {
+            values.pop().unwrap()
+        } else {
+            cx.ty(DSP, TyKind::Tup(values))
+        };
+
+        let status = gen_type(cx, env, &EfiType::Status);
+        cx.ty_path(cx.path_all(DSP,
+                               true,
+                               cx.std_path(&["result", "Result"]),
+                               vec![],
+                               vec![value, status],
+                               vec![]))
+    } else {
+        gen_type(cx, env, &method.ty)
+    };
+
     MethodSig {
         unsafety: Unsafety::Normal,
         constness: dummy_spanned(Constness::NotConst),
         abi: Abi::Rust,
-        decl: cx.fn_decl(args, gen_type(cx, &method.ty, None)),
+        decl: cx.fn_decl(args, ret),
         generics: Generics::default(),
     }
 }
 
-fn gen_getter_sig(cx: &ExtCtxt, field: &EfiField) -> MethodSig {
+fn gen_getter_sig(cx: &ExtCtxt, env: &Env, field: &EfiField) -> MethodSig {
     let name = fresh_name(cx.ident_of("'a"));
     let mut lifetime = Generics::default();
     lifetime.lifetimes = vec![cx.lifetime_def(DSP, name, vec![])];
 
     let ty = match field.ty {
-        EfiType::Ptr(ref ty) => {
+        EfiType::Ptr(ref ty) |
+        EfiType::ConstPtr(ref ty) => {
             cx.ty_rptr(DSP,
-                       gen_type(cx, ty, Some(EfiArgDir::In)),
+                       gen_pointee(cx, env, ty),
                        Some(cx.lifetime(DSP, name)),
                        Mutability::Immutable)
         }
-        ref ty => gen_type(cx, ty, Some(EfiArgDir::In)),
+        ref ty => gen_type(cx, env, ty),
     };
 
     MethodSig {
@@ -250,27 +1185,28 @@ fn gen_getter_sig(cx: &ExtCtxt, field: &EfiField) -> MethodSig {
     }
 }
 
-fn gen_protocol_trait(cx: &ExtCtxt, proto: &EfiProtocol) -> P<Item> {
+fn gen_protocol_trait(cx: &ExtCtxt, env: &Env, proto: &EfiProtocol) -> P<Item> {
     let methods = proto.methods
         .iter()
         .map(|m| {
             TraitItem {
                 id: DID,
                 ident: cx.ident_of(&gen_efisc_name(&m.name)),
-                attrs: vec![],
-                node: TraitItemKind::Method(gen_method_sig(cx, m), None),
+                attrs: if m.deprecated { vec![gen_deprecated(cx)] } else { vec![] },
+                node: TraitItemKind::Method(gen_method_sig(cx, env, m), None),
                 span: DSP,
             }
         });
 
     let accessors = proto.fields
         .iter()
+        .filter(|f| !f.is_reserved)
         .map(|f| {
             TraitItem {
                 id: DID,
                 ident: cx.ident_of(&gen_efisc_name(&f.name)),
                 attrs: vec![],
-                node: TraitItemKind::Method(gen_getter_sig(cx, f), None),
+                node: TraitItemKind::Method(gen_getter_sig(cx, env, f), None),
                 span: DSP,
             }
         });
@@ -280,33 +1216,149 @@ fn gen_protocol_trait(cx: &ExtCtxt, proto: &EfiProtocol) -> P<Item> {
                               P::new(),
                               methods.chain(accessors).collect());
 
-    cx.item(DSP, cx.ident_of(&gen_trait_name(&proto.name)), vec![], trt)
+    let attrs = if proto.deprecated { vec![gen_deprecated(cx)] } else { vec![] };
+
+    cx.item(DSP, cx.ident_of(&gen_trait_name(&proto.name)), attrs, trt)
         .map(|mut i| {
             i.vis = Visibility::Public;
             i
         })
 }
 
-fn gen_method_block(cx: &ExtCtxt, method: &EfiMethod) -> P<Block> {
+/// `Err(status)` for error codes, `Ok(value)` otherwise.
+fn gen_status_check(cx: &ExtCtxt, env: &Env, status: Ident, value: P<Expr>) -> P<Expr> {
+    let paren = |expr| cx.expr(DSP, ExprKind::Paren(expr));
+
+    // error codes are the ones with the high bit set, the cast has to keep the width
+    let (raw, signed) = match env.options.status_as {
+        StatusStyle::Uefi => {
+            let signed = match env.options.pointer_width {
+                Some(32) => "i32",
+                Some(64) => "i64",
+                _ => "isize",
+            };
+            (cx.expr_ident(DSP, status), signed)
+        }
+        StatusStyle::Usize => (cx.expr_ident(DSP, status), "isize"),
+        StatusStyle::Newtype => {
+            (cx.expr_tup_field_access(DSP, cx.expr_ident(DSP, status), 0), "isize")
+        }
+    };
+    let raw = cx.expr_cast(DSP, raw, cx.ty_ident(DSP, cx.ident_of(signed)));
+    let cond = cx.expr_binary(DSP,
+                              BinOpKind::Lt,
+                              paren(raw),
+                              cx.expr_lit(DSP, LitKind::Int(0, LitIntType::Unsuffixed)));
+
+    cx.expr_if(DSP,
+               cond,
+               cx.expr_err(DSP, cx.expr_ident(DSP, status)),
+               Some(cx.expr_ok(DSP, value)))
+}
+
+/// `EFI_UNSUPPORTED` in the configured status style. Error codes have the high bit set, which
+/// depends on the width when that is not known.
+fn gen_unsupported(cx: &ExtCtxt, env: &Env) -> P<Expr> {
+    let lit = |v| cx.expr_lit(DSP, LitKind::Int(v, LitIntType::Unsuffixed));
+    let value = match env.options.pointer_width {
+        Some(32) => lit(0x80000003),
+        Some(64) => lit(0x8000000000000003),
+        _ => {
+            let max = cx.path(DSP, vec![cx.ident_of("usize"), cx.ident_of("max_value")]);
+            let max = cx.expr_call(DSP, cx.expr_path(max), vec![]);
+            let high = cx.expr_binary(DSP, BinOpKind::Shr, max, lit(1));
+            let high = cx.expr_unary(DSP, UnOp::Not, cx.expr(DSP, ExprKind::Paren(high)));
+            cx.expr_binary(DSP, BinOpKind::BitOr, high, lit(3))
+        }
+    };
+
+    match env.options.status_as {
+        StatusStyle::Uefi | StatusStyle::Usize => value,
+        StatusStyle::Newtype => cx.expr_call_ident(DSP, cx.ident_of("Status"), vec![value]),
+    }
+}
+
+fn gen_method_block(cx: &ExtCtxt, env: &Env, method: &EfiMethod) -> P<Block> {
     let paren = |expr| cx.expr(DSP, ExprKind::Paren(expr));
+    let ident = |a: &EfiArg| cx.ident_of(&gen_efisc_name(&a.name));
+
+    let mut stmts: Vec<_> = method.args
+        .iter()
+        .filter_map(|a| returned_pointee(method, a).map(|ty| (a, ty)))
+        .map(|(a, ty)| {
+            let zeroed = cx.expr_call_global(DSP, cx.std_path(&["mem", "zeroed"]), vec![]);
+            cx.stmt_let_typed(DSP, true, ident(a), gen_type(cx, env, ty), zeroed).unwrap()
+        })
+        .collect();
 
     let args = method.args
         .iter()
-        .map(|a| if a.name == "This" {
+        .enumerate()
+        .map(|(i, a)| if is_receiver(i, a) {
             cx.expr_self(DSP)
+        } else if returned_pointee(method, a).is_some() {
+            cx.expr_mut_addr_of(DSP, cx.expr_ident(DSP, ident(a)))
+        } else if a.optional && is_pointer(&a.ty) {
+            let null = match a.ty {
+                EfiType::ConstPtr(_) => "null",
+                _ => "null_mut",
+            };
+            let null = cx.expr_call_global(DSP, cx.std_path(&["ptr", null]), vec![]);
+            cx.expr_method_call(DSP,
+                                cx.expr_ident(DSP, ident(a)),
+                                cx.ident_of("unwrap_or"),
+                                vec![null])
         } else {
-            cx.expr_ident(DSP, cx.ident_of(&gen_efisc_name(&a.name)))
+            cx.expr_ident(DSP, ident(a))
         })
         .collect();
 
     let field = cx.expr_field_access(DSP,
                                      paren(cx.expr_deref(DSP, cx.expr_self(DSP))),
                                      cx.ident_of(&gen_efisc_name(&method.name)));
+    let field = if method.ty == EfiType::Status {
+        // firmware leaves out what it does not support, which the caller gets told as such
+        let fun = cx.ident_of("f");
+        let unsupported = cx.expr_err(DSP, gen_unsupported(cx, env));
+        let unsupported = cx.expr(DSP, ExprKind::Ret(Some(unsupported)));
+        cx.expr_match(DSP,
+                      field,
+                      vec![cx.arm(DSP,
+                                  vec![cx.pat_some(DSP, cx.pat_ident(DSP, fun))],
+                                  cx.expr_ident(DSP, fun)),
+                           cx.arm(DSP, vec![cx.pat_none(DSP)], unsupported)])
+    } else {
+        let missing = format!("{} is not implemented", method.name);
+        cx.expr_method_call(DSP,
+                            field,
+                            cx.ident_of("expect"),
+                            vec![cx.expr_str(DSP, intern_and_get_ident(&missing))])
+    };
 
     let call = cx.expr_call(DSP, paren(field), args);
 
+    if method.ty == EfiType::Status {
+        let status = cx.ident_of("status");
+        stmts.push(cx.stmt_let(DSP, false, status, call));
+
+        let mut values: Vec<_> = method.args
+            .iter()
+            .filter(|a| returned_pointee(method, a).is_some())
+            .map(|a| cx.expr_ident(DSP, ident(a)))
+            .collect();
+        let value = if values.len() == 1 {
+            values.pop().unwrap()
+        } else {
+            cx.expr_tuple(DSP, values)
+        };
+
+        stmts.push(cx.stmt_expr(gen_status_check(cx, env, status, value)));
+    } else {
+        stmts.push(cx.stmt_expr(call));
+    }
+
     cx.block_expr(cx.expr_block(P(Block {
-        stmts: vec![cx.stmt_expr(call)],
+        stmts: stmts,
         id: DID,
         rules: BlockCheckMode::Unsafe(UnsafeSource::CompilerGenerated),
         span: DSP,
@@ -331,7 +1383,7 @@ fn gen_getter_block(cx: &ExtCtxt, field: &EfiField) -> P<Block> {
     })))
 }
 
-fn gen_protocol_impl(cx: &ExtCtxt, proto: &EfiProtocol) -> P<Item> {
+fn gen_protocol_impl(cx: &ExtCtxt, env: &Env, proto: &EfiProtocol) -> P<Item> {
     let methods = proto.methods
         .iter()
         .map(|m| {
@@ -341,13 +1393,15 @@ fn gen_protocol_impl(cx: &ExtCtxt, proto: &EfiProtocol) -> P<Item> {
                 vis: Visibility::Inherited,
                 defaultness: Defaultness::Final,
                 attrs: vec![],
-                node: ImplItemKind::Method(gen_method_sig(cx, m), gen_method_block(cx, m)),
+                node: ImplItemKind::Method(gen_method_sig(cx, env, m),
+                                           gen_method_block(cx, env, m)),
                 span: DSP,
             }
         });
 
     let accessors = proto.fields
         .iter()
+        .filter(|f| !f.is_reserved)
         .map(|f| {
             ImplItem {
                 id: DID,
@@ -355,14 +1409,15 @@ fn gen_protocol_impl(cx: &ExtCtxt, proto: &EfiProtocol) -> P<Item> {
                 vis: Visibility::Inherited,
                 defaultness: Defaultness::Final,
                 attrs: vec![],
-                node: ImplItemKind::Method(gen_getter_sig(cx, f), gen_getter_block(cx, f)),
+                node: ImplItemKind::Method(gen_getter_sig(cx, env, f),
+                                           gen_getter_block(cx, f)),
                 span: DSP,
             }
         });
 
     let trait_ident = cx.ident_of(&gen_trait_name(&proto.name));
-    let proto_ident = cx.ident_of(&gen_eficc_name(&proto.name));
-    let ty = cx.ty_ptr(DSP, cx.ty_ident(DSP, proto_ident), Mutability::Immutable);
+    let proto_ident = cx.ident_of(&env.rust_name(&proto.name));
+    let ty = cx.ty_ptr(DSP, cx.ty_ident(DSP, proto_ident), Mutability::Mutable);
     let imp = ItemKind::Impl(Unsafety::Normal,
                              ImplPolarity::Positive,
                              Generics::default(),
@@ -373,29 +1428,1159 @@ fn gen_protocol_impl(cx: &ExtCtxt, proto: &EfiProtocol) -> P<Item> {
     cx.item(DSP, trait_ident, vec![], imp)
 }
 
-pub fn gen_module(module: &EfiModule) -> io::Result<()> {
+/// The `Protocol` trait, which gives generic code the GUID of a protocol.
+fn gen_guid_trait(cx: &ExtCtxt) -> P<Item> {
+    let guid = TraitItem {
+        id: DID,
+        ident: cx.ident_of("GUID"),
+        attrs: vec![],
+        node: TraitItemKind::Const(cx.ty_ident(DSP, cx.ident_of("Guid")), None),
+        span: DSP,
+    };
+    let trt = ItemKind::Trait(Unsafety::Normal, Generics::default(), P::new(), vec![guid]);
+
+    cx.item(DSP, cx.ident_of("Protocol"), vec![], trt)
+        .map(|mut i| {
+            i.vis = Visibility::Public;
+            i
+        })
+}
+
+fn gen_guid_impl(cx: &ExtCtxt, env: &Env, proto: &EfiProtocol, guid: &EfiGuid) -> P<Item> {
+    let gen_lit = |v| cx.expr_lit(DSP, LitKind::Int(v, LitIntType::Unsuffixed));
+
+    let data4 = guid.data4.iter().map(|&b| gen_lit(b as u64)).collect();
+    let fields = vec![cx.field_imm(DSP, cx.ident_of("data1"), gen_lit(guid.data1 as u64)),
+                      cx.field_imm(DSP, cx.ident_of("data2"), gen_lit(guid.data2 as u64)),
+                      cx.field_imm(DSP, cx.ident_of("data3"), gen_lit(guid.data3 as u64)),
+                      cx.field_imm(DSP, cx.ident_of("data4"), cx.expr_vec(DSP, data4))];
+    let value = ImplItem {
+        id: DID,
+        ident: cx.ident_of("GUID"),
+        vis: Visibility::Inherited,
+        defaultness: Defaultness::Final,
+        attrs: vec![],
+        node: ImplItemKind::Const(cx.ty_ident(DSP, cx.ident_of("Guid")),
+                                  cx.expr_struct_ident(DSP, cx.ident_of("Guid"), fields)),
+        span: DSP,
+    };
+
+    let proto_ident = cx.ident_of(&env.rust_name(&proto.name));
+    let imp = ItemKind::Impl(Unsafety::Normal,
+                             ImplPolarity::Positive,
+                             Generics::default(),
+                             Some(cx.trait_ref(cx.path_ident(DSP, cx.ident_of("Protocol")))),
+                             cx.ty_ident(DSP, proto_ident),
+                             vec![value]);
+
+    cx.item(DSP, proto_ident, vec![], imp)
+}
+
+/// `locate::<P>(bs)`, which finds the first instance of a protocol through
+/// `EFI_BOOT_SERVICES.LocateProtocol`. `None` if the module does not define that.
+fn gen_locate(cx: &ExtCtxt, env: &Env) -> Option<P<Item>> {
+    let defined = env.module
+        .records
+        .iter()
+        .find(|r| r.name == "EFI_BOOT_SERVICES")
+        .map_or(false, |r| {
+            r.fields.iter().any(|f| {
+                f.name == "LocateProtocol" &&
+                match f.ty {
+                    EfiType::Id(ref name) => env.module.callbacks.iter().any(|c| c.name == *name),
+                    _ => false,
+                }
+            })
+        });
+    if !defined {
+        return None;
+    }
+
+    let paren = |expr| cx.expr(DSP, ExprKind::Paren(expr));
+    let p = cx.ident_of("P");
+    let bs = cx.ident_of("bs");
+    let interface = cx.ident_of("interface");
+    let status = cx.ident_of("status");
+    let void = gen_pointee(cx, env, &EfiType::Void);
+    let null = cx.path_all(DSP,
+                           true,
+                           cx.std_path(&["ptr", "null_mut"]),
+                           vec![],
+                           vec![void],
+                           vec![]);
+    let null = cx.expr_call(DSP, cx.expr_path(null), vec![]);
+
+    // the pointer types of the arguments follow the header, casts adapt to them
+    let guid = cx.expr_path(cx.path(DSP, vec![p, cx.ident_of("GUID")]));
+    let guid = cx.expr_cast(DSP,
+                            cx.expr_addr_of(DSP, guid),
+                            cx.ty_ptr(DSP,
+                                      cx.ty_ident(DSP, cx.ident_of("Guid")),
+                                      Mutability::Immutable));
+    let args = vec![cx.expr_cast(DSP, guid, cx.ty_infer(DSP)),
+                    cx.expr_cast(DSP, null.clone(), cx.ty_infer(DSP)),
+                    cx.expr_cast(DSP,
+                                 cx.expr_mut_addr_of(DSP, cx.expr_ident(DSP, interface)),
+                                 cx.ty_infer(DSP))];
+    let locate = cx.expr_field_access(DSP,
+                                      cx.expr_ident(DSP, bs),
+                                      cx.ident_of(&gen_efisc_name("LocateProtocol")));
+    let call = cx.expr_call(DSP, paren(locate), args);
+
+    let ptr = cx.ty_ptr(DSP, cx.ty_ident(DSP, p), Mutability::Immutable);
+    let value = cx.expr_addr_of(DSP,
+                                cx.expr_deref(DSP,
+                                              paren(cx.expr_cast(DSP,
+                                                                 cx.expr_ident(DSP, interface),
+                                                                 ptr))));
+    let stmts = vec![cx.stmt_let(DSP, true, interface, null),
+                     cx.stmt_let(DSP, false, status, call),
+                     cx.stmt_expr(gen_status_check(cx, env, status, value))];
+    let body = cx.block(DSP, stmts);
+
+    let bs_ty = cx.ty_rptr(DSP,
+                           gen_type(cx, env, &EfiType::Id(String::from("EFI_BOOT_SERVICES"))),
+                           None,
+                           Mutability::Immutable);
+    let ret = cx.ty_path(cx.path_all(DSP,
+                                     true,
+                                     cx.std_path(&["result", "Result"]),
+                                     vec![],
+                                     vec![cx.ty_rptr(DSP,
+                                                     cx.ty_ident(DSP, p),
+                                                     None,
+                                                     Mutability::Immutable),
+                                          gen_type(cx, env, &EfiType::Status)],
+                                     vec![]));
+    let bound = cx.typarambound(cx.path_ident(DSP, cx.ident_of("Protocol")));
+    let generics = Generics {
+        lifetimes: vec![],
+        ty_params: P::from_vec(vec![cx.typaram(DSP, p, P::from_vec(vec![bound]), None)]),
+        where_clause: WhereClause {
+            id: DID,
+            predicates: vec![],
+        },
+        span: DSP,
+    };
+    let fun = ItemKind::Fn(cx.fn_decl(vec![cx.arg(DSP, bs, bs_ty)], ret),
+                           Unsafety::Unsafe,
+                           dummy_spanned(Constness::NotConst),
+                           Abi::Rust,
+                           generics,
+                           body);
+
+    Some(cx.item(DSP, cx.ident_of("locate"), vec![], fun).map(|mut i| {
+        i.vis = Visibility::Public;
+        i
+    }))
+}
+
+pub fn gen_module(module: &EfiModule, options: &RustOptions, out: &mut Write) -> io::Result<()> {
     let sess = &ParseSess::new();
     let mut ml = DummyResolver;
-    let cx = ExtCtxt::new(sess,
-                          vec![],
-                          ExpansionConfig::default("efi".into()),
-                          &mut ml);
-
-    let records = module.records.iter().map(|r| gen_record(&cx, r));
-    let enums = module.enums.iter().map(|r| gen_enum(&cx, r));
-    let protos = module.protocols.iter().map(|p| gen_bare_protocol(&cx, p));
-    let traits = module.protocols.iter().map(|p| gen_protocol_trait(&cx, p));
-    let impls = module.protocols.iter().map(|p| gen_protocol_impl(&cx, p));
+    let mut cx = ExtCtxt::new(sess,
+                              vec![],
+                              ExpansionConfig::default("efi".into()),
+                              &mut ml);
+    // `Option`, `Result` and friends are spelled with their `::std` path
+    cx.crate_root = Some("std");
+
+    let mut item_attrs = HashMap::new();
+    for (name, attrs) in &options.item_attrs {
+        let attrs = try!(attrs.iter().map(|a| gen_attr(&cx, a)).collect());
+        item_attrs.insert(name.as_str(), attrs);
+    }
+
+    let env = Env {
+        module: module,
+        options: options,
+        item_attrs: item_attrs,
+        names: rust_names(module),
+    };
+
+    // externally provided types are only referenced, never defined
+    let sorted_records = try!(module.sorted_records()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string())));
+    let mut records = Vec::new();
+    for record in sorted_records.iter()
+        .filter(|r| !env.is_extern(&r.name) && !env.is_blocklisted(&r.name)) {
+        records.push(env.with_item_attrs(try!(gen_record(&cx, &env, record)), &record.name));
+    }
+    let mut accessors = Vec::new();
+    if options.emit_array_accessors {
+        for record in module.records
+            .iter()
+            .filter(|r| !env.is_extern(&r.name) && !env.is_blocklisted(&r.name)) {
+            if let Some(item) = gen_array_accessors(&cx, &env, record) {
+                accessors.push(item);
+            }
+        }
+    }
+    let enums = module.enums
+        .iter()
+        .filter(|e| !env.is_extern(&e.name) && !env.is_blocklisted(&e.name))
+        .map(|e| env.with_item_attrs(gen_enum(&cx, &env, e), &e.name));
+    let callbacks = module.callbacks
+        .iter()
+        .filter(|c| !env.is_extern(&c.name) && !env.is_blocklisted(&c.name))
+        .map(|c| env.with_item_attrs(gen_callback(&cx, &env, c), &c.name));
+    let aliases = module.aliases
+        .iter()
+        .filter(|&&(ref name, _)| !env.is_extern(name) && !env.is_blocklisted(name))
+        .map(|&(ref name, ref ty)| env.with_item_attrs(gen_alias(&cx, &env, name, ty), name));
+    let protocols: Vec<_> = module.protocols.iter().filter(|p| !env.is_extern(&p.name)).collect();
+    let protos = protocols.iter()
+        .map(|p| env.with_item_attrs(gen_bare_protocol(&cx, &env, p), &p.name));
+    let traits = protocols.iter()
+        .map(|p| env.with_item_attrs(gen_protocol_trait(&cx, &env, p), &p.name));
+    let impls = protocols.iter()
+        .map(|p| env.with_item_attrs(gen_protocol_impl(&cx, &env, p), &p.name));
+    let status = if options.status_as == StatusStyle::Newtype {
+        Some(gen_status(&cx))
+    } else {
+        None
+    };
+    let has_guids = options.emit_protocol_trait && protocols.iter().any(|p| p.guid.is_some());
+    let guid = if has_guids || module.iter_types().any(|ty| *ty == EfiType::Guid) {
+        Some(gen_guid(&cx))
+    } else {
+        None
+    };
+    let consts = gen_consts(&cx, &env);
+    let registry = if options.emit_registry {
+        vec![gen_protocol_info(&cx), gen_registry(&cx, &protocols)]
+    } else {
+        vec![]
+    };
+    let mut guid_impls = Vec::new();
+    if options.emit_protocol_trait {
+        guid_impls.push(gen_guid_trait(&cx));
+        guid_impls.extend(protocols.iter().filter_map(|p| {
+            p.guid.as_ref().map(|guid| {
+                env.with_item_attrs(gen_guid_impl(&cx, &env, p, guid), &p.name)
+            })
+        }));
+        guid_impls.extend(gen_locate(&cx, &env));
+    }
     let module = Mod {
         inner: DSP,
-        items: records.chain(enums).chain(protos).chain(traits).chain(impls).collect(),
+        items: status.into_iter()
+            .chain(guid)
+            .chain(consts)
+            .chain(records)
+            .chain(accessors)
+            .chain(enums)
+            .chain(callbacks)
+            .chain(aliases)
+            .chain(protos)
+            .chain(traits)
+            .chain(impls)
+            .chain(guid_impls)
+            .chain(registry)
+            .collect(),
     };
 
-    let mut ps = pprust::rust_printer(Box::from(io::stdout()));
-    ps.s = pp::mk_printer(Box::from(io::stdout()), 100);
+    let mut ps = pprust::rust_printer(Box::new(io::sink()));
+    ps.s = pp::mk_printer(Box::new(out), 100);
     try!(ps.print_mod(&module, &[]));
     try!(ps.print_remaining_comments());
     try!(pp::eof(&mut ps.s));
     try!(ps.s.out.flush());
     Ok(())
 }
+
+/// Parses `efi_header` and generates bindings for protocol `name` and the declarations it
+/// depends on only.
+pub fn gen_protocol(efi_header: &str,
+                    name: &str,
+                    options: &RustOptions)
+                    -> Result<String, Box<Error>> {
+    let module = try!(parse(efi_header));
+    let closure = match module.closure(name) {
+        Some(closure) => closure,
+        None => return Err(Box::from(format!("unknown protocol {}", name))),
+    };
+
+    let mut out = Vec::new();
+    try!(gen_module(&closure, options, &mut out));
+    Ok(try!(String::from_utf8(out)))
+}
+
+/// Parses `efi_header` and generates the bindings for everything it declares.
+pub fn parse_and_gen(efi_header: &str, options: &GenOptions) -> Result<String, Box<Error>> {
+    let module = try!(parse_with_options(efi_header, &options.parse));
+    if options.check_collisions {
+        try!(collisions_to_error(&module));
+    }
+
+    let mut out = Vec::new();
+    try!(gen_module(&module, &options.rust, &mut out));
+    Ok(try!(String::from_utf8(out)))
+}
+
+fn collisions_to_error(module: &EfiModule) -> Result<(), Box<Error>> {
+    check_collisions(module).map_err(|collisions| {
+        let names: Vec<_> = collisions.iter()
+            .map(|&(ref a, ref b)| format!("{} and {}", a, b))
+            .collect();
+        Box::from(format!("colliding names: {}", names.join(", ")))
+    })
+}
+
+/// EFI names of the records, enums, callbacks, aliases and protocols of `module`, in that order.
+fn item_names(module: &EfiModule) -> Vec<&String> {
+    module.records
+        .iter()
+        .map(|r| &r.name)
+        .chain(module.enums.iter().map(|e| &e.name))
+        .chain(module.callbacks.iter().map(|c| &c.name))
+        .chain(module.aliases.iter().map(|a| &a.0))
+        .chain(module.protocols.iter().map(|p| &p.name))
+        .collect()
+}
+
+/// Rust identifiers of the records, enums, callbacks, aliases and protocols of `module` by EFI
+/// name. EFI names that map to the same identifier are told apart by a numeric suffix, the first
+/// one in module order keeps the plain name.
+fn rust_names(module: &EfiModule) -> HashMap<&str, String> {
+    let mut names = HashMap::new();
+    let mut taken = HashSet::new();
+    for name in item_names(module) {
+        if names.contains_key(name.as_str()) {
+            continue;
+        }
+        let base = gen_eficc_name(name);
+        let mut ident = base.clone();
+        let mut n = 1;
+        while taken.contains(&ident) {
+            n += 1;
+            ident = format!("{}{}", base, n);
+        }
+        taken.insert(ident.clone());
+        names.insert(name.as_str(), ident);
+    }
+    names
+}
+
+/// Reports pairs of distinct EFI names that map to the same Rust identifier. The second one of
+/// a pair is emitted with a numeric suffix, unless it is the name of a protocol's trait, which
+/// is never renamed.
+pub fn check_collisions(module: &EfiModule) -> Result<(), Vec<(String, String)>> {
+    let names = rust_names(module);
+    let owners: HashMap<&str, &str> = names.iter()
+        .map(|(&name, ident)| (ident.as_str(), name))
+        .collect();
+
+    let mut collisions = Vec::new();
+    let mut seen = HashSet::new();
+    for name in item_names(module) {
+        if !seen.insert(name) {
+            continue;
+        }
+        let base = gen_eficc_name(name);
+        if names[name.as_str()] != base {
+            collisions.push((String::from(owners[base.as_str()]), name.clone()));
+        }
+    }
+    for proto in &module.protocols {
+        if let Some(owner) = owners.get(gen_trait_name(&proto.name).as_str()) {
+            collisions.push((String::from(*owner), proto.name.clone()));
+        }
+    }
+
+    if collisions.is_empty() {
+        Ok(())
+    } else {
+        Err(collisions)
+    }
+}
+
+/// Generates the bindings for `module` into `out_path`, creating missing parent directories.
+///
+/// An existing file is only rewritten if its content changes, so build scripts do not trigger
+/// needless rebuilds.
+pub fn write_bindings<P: AsRef<Path>>(module: &EfiModule,
+                                      out_path: P,
+                                      options: &RustOptions)
+                                      -> io::Result<()> {
+    let mut bindings = Vec::new();
+    try!(gen_module(module, options, &mut bindings));
+    write_if_changed(out_path.as_ref(), &bindings)
+}
+
+fn write_if_changed(out_path: &Path, bindings: &[u8]) -> io::Result<()> {
+    let mut current = Vec::new();
+    if let Ok(mut file) = File::open(out_path) {
+        try!(file.read_to_end(&mut current));
+    }
+    if current == bindings {
+        return Ok(());
+    }
+
+    match out_path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => try!(fs::create_dir_all(dir)),
+        _ => {}
+    }
+    let mut file = try!(File::create(out_path));
+    file.write_all(bindings)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process::{self, Command};
+    use std::sync::atomic::{AtomicUsize, ATOMIC_USIZE_INIT, Ordering};
+    use std::thread;
+    use std::time::Duration;
+
+    fn field(name: &str, ty: EfiType) -> EfiField {
+        EfiField {
+            name: String::from(name),
+            ty: ty,
+            is_reserved: false,
+            bit_width: None,
+            len_const: None,
+            doc: None,
+        }
+    }
+
+    fn record(name: &str, fields: Vec<EfiField>) -> EfiRecord {
+        EfiRecord {
+            name: String::from(name),
+            fields: fields,
+            kind: EfiRecordKind::Struct,
+            doc: None,
+            ms_layout: false,
+            anonymous: false,
+        }
+    }
+
+    #[test]
+    fn write_bindings_only_rewrites_changes() {
+        let module = EfiModule {
+            records: vec![record("EFI_FOO", vec![field("Bar", EfiType::UInt32)])],
+            ..EfiModule::default()
+        };
+        let dir = env::temp_dir().join(format!("efi-bindgen-write-{}", process::id()));
+        let path = dir.join("out").join("bindings.rs");
+        let _ = fs::remove_dir_all(&dir);
+
+        write_bindings(&module, &path, &RustOptions::default()).unwrap();
+        let mut content = String::new();
+        File::open(&path).unwrap().read_to_string(&mut content).unwrap();
+        assert!(content.contains("pub struct Foo"));
+        let modified = fs::metadata(&path).unwrap().modified().unwrap();
+
+        // long enough for any timestamp granularity to tell a rewrite apart
+        thread::sleep(Duration::from_millis(1100));
+        write_bindings(&module, &path, &RustOptions::default()).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().modified().unwrap(), modified);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    fn generate(module: &EfiModule, options: &RustOptions) -> String {
+        let mut out = Vec::new();
+        gen_module(module, options, &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    /// The attributes directly in front of `pub struct <name>`.
+    fn struct_attrs<'a>(source: &'a str, name: &str) -> Vec<&'a str> {
+        let item = format!("pub struct {}", name);
+        let lines: Vec<&str> = source.lines().map(|l| l.trim()).collect();
+        let end = lines.iter().position(|l| l.starts_with(&item)).expect(name);
+        let start = lines[..end].iter().rposition(|l| !l.starts_with("#")).map_or(0, |i| i + 1);
+        lines[start..end].to_vec()
+    }
+
+    #[test]
+    fn derives_only_what_members_allow() {
+        let mut ip = record("EFI_IP_ADDRESS", vec![field("Addr", EfiType::UInt32)]);
+        ip.kind = EfiRecordKind::Union;
+        let id = |name: &str| EfiType::Id(String::from(name));
+        let module = EfiModule {
+            records: vec![record("EFI_PLAIN", vec![field("Size", EfiType::UInt32)]),
+                          record("EFI_HEADER",
+                                 vec![field("Size", EfiType::UInt32),
+                                      field("Data", EfiType::Array(Box::new(EfiType::UInt8), 0))]),
+                          ip,
+                          record("EFI_NODE", vec![field("Address", id("EFI_IP_ADDRESS"))]),
+                          record("EFI_WRAPPED", vec![field("Inner", id("EFI_EXTERN"))])],
+            extern_types: vec![(String::from("EFI_EXTERN"), String::from("ext::Extern"))]
+                .into_iter()
+                .collect(),
+            ..EfiModule::default()
+        };
+        let source = generate(&module, &RustOptions::default());
+
+        assert!(struct_attrs(&source, "Plain").contains(&"#[derive(Debug, Clone, Copy)]"));
+        assert!(struct_attrs(&source, "Header").contains(&"#[derive(Debug)]"));
+        assert!(struct_attrs(&source, "Node").contains(&"#[derive(Clone, Copy)]"));
+        assert!(!struct_attrs(&source, "Wrapped").iter().any(|a| a.starts_with("#[derive")));
+    }
+
+    #[test]
+    fn extern_types_are_referenced_by_path() {
+        let id = |name: &str| EfiType::Id(String::from(name));
+        let module = EfiModule {
+            records: vec![record("EFI_HANDLE_DATA", vec![field("Raw", EfiType::UInt64)]),
+                          record("EFI_USER",
+                                 vec![field("Data", EfiType::Ptr(Box::new(id("EFI_HANDLE_DATA")))),
+                                      field("Time", id("EFI_TIME"))])],
+            extern_types: vec![(String::from("EFI_HANDLE_DATA"), String::from("::ext::HandleData")),
+                               (String::from("EFI_TIME"), String::from("time::Time"))]
+                .into_iter()
+                .collect(),
+            ..EfiModule::default()
+        };
+        let source = generate(&module, &RustOptions::default());
+
+        assert!(source.contains("pub data: *mut ::ext::HandleData,"), "{}", source);
+        assert!(source.contains("pub time: time::Time,"), "{}", source);
+        // defined by the module as well, but the outside definition wins
+        assert!(!source.contains("struct HandleData"), "{}", source);
+        assert!(!source.contains("raw"), "{}", source);
+    }
+
+    #[test]
+    fn item_attrs_are_emitted_on_their_item() {
+        let mut module = foo_protocol(Vec::new());
+        module.records.push(record("EFI_PLAIN", vec![field("Size", EfiType::UInt32)]));
+        let attrs = vec![String::from("#[cfg(feature = \"foo\")]"),
+                         String::from("#[allow(dead_code)]")];
+        let options = RustOptions {
+            item_attrs: vec![(String::from("EFI_FOO_PROTOCOL"), vec![attrs[0].clone()]),
+                             (String::from("EFI_PLAIN"), attrs.clone())]
+                .into_iter()
+                .collect(),
+            ..RustOptions::default()
+        };
+        let source = generate(&module, &options);
+        let lines: Vec<_> = source.lines().map(|l| l.trim()).collect();
+        let attrs_of = |item: &str| {
+            let end = lines.iter().position(|l| l.starts_with(item)).expect(item);
+            let start = lines[..end].iter().rposition(|l| !l.starts_with("#")).map_or(0, |i| i + 1);
+            lines[start..end].to_vec()
+        };
+
+        assert!(struct_attrs(&source, "Plain").contains(&"#[cfg(feature = \"foo\")]"));
+        assert!(struct_attrs(&source, "Plain").contains(&"#[allow(dead_code)]"));
+        for item in &["pub struct Foo {", "pub trait FooProtocol {", "impl FooProtocol for"] {
+            assert_eq!(attrs_of(item).iter().filter(|a| a.starts_with("#[cfg")).count(),
+                       1,
+                       "{}",
+                       source);
+        }
+
+        let options = RustOptions {
+            item_attrs: vec![(String::from("EFI_PLAIN"), vec![String::from("cfg(foo)")])]
+                .into_iter()
+                .collect(),
+            ..RustOptions::default()
+        };
+        let mut out = Vec::new();
+        assert!(gen_module(&module, &options, &mut out).is_err());
+    }
+
+    #[test]
+    fn blocklisted_types_are_referenced_but_not_defined() {
+        let id = |name: &str| EfiType::Id(String::from(name));
+        let module = EfiModule {
+            records: vec![record("EFI_TIME", vec![field("Year", EfiType::UInt16)]),
+                          record("EFI_USER", vec![field("Created", id("EFI_TIME"))])],
+            aliases: vec![(String::from("EFI_TPL"), EfiType::UIntN)],
+            ..EfiModule::default()
+        };
+        let options = RustOptions {
+            type_blocklist: vec![String::from("EFI_TIME"), String::from("EFI_TPL")],
+            ..RustOptions::default()
+        };
+        let source = generate(&module, &options);
+
+        assert!(source.contains("pub created: Time,"), "{}", source);
+        assert!(source.contains("pub struct User {"), "{}", source);
+        assert!(!source.contains("struct Time"), "{}", source);
+        assert!(!source.contains("type Tpl"), "{}", source);
+    }
+
+    #[test]
+    fn registry_lists_every_protocol() {
+        let mut module = foo_protocol(Vec::new());
+        module.protocols[0].guid = Some(EfiGuid {
+            data1: 0x01234567,
+            data2: 0x89ab,
+            data3: 0xcdef,
+            data4: [0, 1, 2, 3, 4, 5, 6, 7],
+        });
+        module.protocols.push(EfiProtocol {
+            name: String::from("EFI_BAR_PROTOCOL"),
+            methods: Vec::new(),
+            fields: Vec::new(),
+            deprecated: false,
+            guid: None,
+            opaque: true,
+        });
+
+        let source = generate(&module, &RustOptions::default());
+        assert!(!source.contains("PROTOCOLS"), "{}", source);
+
+        let options = RustOptions { emit_registry: true, ..RustOptions::default() };
+        let source = generate(&module, &options);
+        let flat = source.split_whitespace().collect::<Vec<_>>().join(" ");
+        assert!(flat.contains("pub struct ProtocolInfo {"), "{}", source);
+        assert!(flat.contains("pub const PROTOCOLS: &'static [ProtocolInfo] = &["), "{}", source);
+        assert!(flat.contains("ProtocolInfo{name: \"EFI_FOO_PROTOCOL\", \
+                               guid: ::std::option::Option::Some([103, 69, 35, 1, 171, 137, 239, \
+                               205, 0, 1, 2, 3, 4, 5, 6, 7]), methods: 1,}"),
+                "{}",
+                source);
+        assert!(flat.contains("ProtocolInfo{name: \"EFI_BAR_PROTOCOL\", \
+                               guid: ::std::option::Option::None, methods: 0,}"),
+                "{}",
+                source);
+        if let Err(e) = compile_check(&source) {
+            panic!("{}\n{}", source, e);
+        }
+    }
+
+    #[test]
+    fn callbacks_and_aliases_derive_like_what_they_stand_for() {
+        let id = |name: &str| EfiType::Id(String::from(name));
+        let module = EfiModule {
+            records: vec![record("EFI_EVENT_DATA",
+                                 vec![field("Notify", id("EFI_EVENT_NOTIFY")),
+                                      field("Tpl", id("EFI_TPL"))]),
+                          record("EFI_HEADER",
+                                 vec![field("Data", EfiType::Array(Box::new(EfiType::UInt8), 0))]),
+                          record("EFI_WRAPPED", vec![field("Header", id("EFI_HEADER_ALIAS"))])],
+            callbacks: vec![EfiMethod {
+                                name: String::from("EFI_EVENT_NOTIFY"),
+                                ty: EfiType::Void,
+                                args: vec![arg("Context", EfiType::Ptr(Box::new(EfiType::Void)),
+                                               EfiArgDir::In)],
+                                abi: EfiAbi::Efiapi,
+                                deprecated: false,
+                                doc: None,
+                                typedef: None,
+                                const_ptr: false,
+                                slices: Vec::new(),
+                            }],
+            aliases: vec![(String::from("EFI_TPL"), EfiType::UIntN),
+                          (String::from("EFI_HEADER_ALIAS"), id("EFI_HEADER"))],
+            ..EfiModule::default()
+        };
+        let source = generate(&module, &RustOptions::default());
+
+        assert!(struct_attrs(&source, "EventData").contains(&"#[derive(Debug, Clone, Copy)]"));
+        assert!(struct_attrs(&source, "Wrapped").contains(&"#[derive(Debug)]"));
+    }
+
+    #[test]
+    fn enum_discriminants_fit_their_repr() {
+        let variant = |name: &str, value| {
+            EfiVariant {
+                name: String::from(name),
+                value: Some(value),
+            }
+        };
+        let enm = |name: &str, signed, values: Vec<EfiVariant>| {
+            EfiEnum { name: String::from(name), fields: values, signed: signed }
+        };
+        let module = EfiModule {
+            enums: vec![enm("EFI_SMALL", false, vec![variant("A", 0), variant("B", 0x7fffffff)]),
+                        enm("EFI_FLAGS",
+                            false,
+                            vec![variant("Low", 1), variant("High", 0x80000000)]),
+                        enm("EFI_WIDE", false, vec![variant("Huge", 0x100000000)]),
+                        enm("EFI_DELTA", true, vec![variant("Down", -1i64 as u64)]),
+                        enm("EFI_LONG", true, vec![variant("Far", -0x80000001i64 as u64)])],
+            ..EfiModule::default()
+        };
+        let options = RustOptions { pointer_width: Some(32), ..RustOptions::default() };
+        let source = generate(&module, &options);
+        let repr = |name: &str| {
+            let item = format!("pub enum {} ", name);
+            let lines: Vec<&str> = source.lines().map(|l| l.trim()).collect();
+            let end = lines.iter().position(|l| l.starts_with(&item)).expect(name);
+            lines[..end].iter().rev().find(|l| l.starts_with("#[repr(")).unwrap().to_string()
+        };
+
+        assert_eq!(repr("Small"), "#[repr(C)]");
+        assert_eq!(repr("Flags"), "#[repr(u32)]");
+        assert_eq!(repr("Wide"), "#[repr(u64)]");
+        assert_eq!(repr("Delta"), "#[repr(C)]");
+        assert_eq!(repr("Long"), "#[repr(i64)]");
+        assert!(source.contains("High = 2147483648,"), "{}", source);
+        assert!(source.contains("Down = -1,"), "{}", source);
+        if let Err(e) = compile_check(&source) {
+            panic!("{}\n{}", source, e);
+        }
+    }
+
+    #[test]
+    fn array_accessors() {
+        let array = |ty, len| EfiType::Array(Box::new(ty), len);
+        let module = EfiModule {
+            records: vec![record("EFI_DEVICE",
+                                 vec![field("Name", array(EfiType::Char16, 8)),
+                                      field("Mac", array(EfiType::UInt8, 6)),
+                                      field("Id", EfiType::UInt32),
+                                      field("Data", array(EfiType::UInt8, 0))])],
+            ..EfiModule::default()
+        };
+        let source = generate(&module, &RustOptions::default());
+        assert!(!source.contains("impl Device"), "{}", source);
+
+        let options = RustOptions { emit_array_accessors: true, ..RustOptions::default() };
+        let source = generate(&module, &options);
+        let flat = source.split_whitespace().collect::<Vec<_>>().join(" ");
+        assert!(flat.contains("impl Device {"), "{}", source);
+        assert!(flat.contains("pub fn name_slice(&self) -> &[u16] { &self.name }"),
+                "{}",
+                source);
+        assert!(flat.contains("pub fn name_str(&self) -> &[u16] { \
+                               &self.name[..self.name.iter().position(|c| *c == 0)\
+                               .unwrap_or(self.name.len())] }"),
+                "{}",
+                source);
+        assert!(flat.contains("pub fn mac_slice(&self) -> &[u8] { &self.mac }"), "{}", source);
+        assert!(!flat.contains("mac_str"), "{}", source);
+        assert!(!flat.contains("id_slice"), "{}", source);
+        assert!(!flat.contains("data_slice"), "{}", source);
+        if let Err(e) = compile_check(&source) {
+            panic!("{}\n{}", source, e);
+        }
+    }
+
+    #[test]
+    fn status_styles() {
+        let module = EfiModule {
+            records: vec![record("EFI_RESULT", vec![field("Status", EfiType::Status)])],
+            ..EfiModule::default()
+        };
+        let options = |status_as, pointer_width| {
+            RustOptions {
+                status_as: status_as,
+                pointer_width: pointer_width,
+                ..RustOptions::default()
+            }
+        };
+
+        let source = generate(&module, &options(StatusStyle::Newtype, None));
+        assert!(source.contains("#[repr(transparent)]"));
+        assert!(source.contains("pub struct Status(pub usize);"));
+        assert!(source.contains("pub status: Status,"));
+
+        let source = generate(&module, &options(StatusStyle::Uefi, Some(32)));
+        assert!(source.contains("pub status: u32,"));
+        let source = generate(&module, &options(StatusStyle::Uefi, Some(64)));
+        assert!(source.contains("pub status: u64,"));
+        let source = generate(&module, &options(StatusStyle::Uefi, None));
+        assert!(source.contains("pub status: usize,"));
+    }
+
+    fn bitfield(name: &str, ty: EfiType, width: u32) -> EfiField {
+        EfiField { bit_width: Some(width), ..field(name, ty) }
+    }
+
+    #[test]
+    fn bitfields_share_units() {
+        let module = EfiModule {
+            records: vec![record("EFI_MODE",
+                                 vec![field("Value", EfiType::UInt32),
+                                      bitfield("Low", EfiType::UInt32, 4),
+                                      bitfield("High", EfiType::UInt32, 4)])],
+            ..EfiModule::default()
+        };
+        let source = generate(&module, &RustOptions::default());
+
+        assert!(source.contains("pub value: u32,"));
+        assert!(source.contains("Bitfields `Low`: bits 0-3, `High`: bits 4-7"));
+        assert!(source.contains("pub bitfield_1: u32,"));
+        assert!(!source.contains("pub low"));
+    }
+
+    #[test]
+    fn bitfields_in_the_tail_of_another_field_are_refused() {
+        // SysV puts the bitfield into the three bytes after `Flag`
+        let module = EfiModule {
+            records: vec![record("EFI_TAIL",
+                                 vec![field("Flag", EfiType::UInt8),
+                                      bitfield("Bits", EfiType::UInt32, 4)])],
+            ..EfiModule::default()
+        };
+        let mut out = Vec::new();
+        assert!(gen_module(&module, &RustOptions::default(), &mut out).is_err());
+    }
+
+    #[test]
+    fn builder_needs_a_header() {
+        assert!(Builder::new().clang_arg("-DFOO").generate().is_err());
+    }
+
+    #[test]
+    fn colliding_names_get_suffixes() {
+        let module = EfiModule {
+            records: vec![record("EFI_FOO", vec![field("Bar", EfiType::UInt32)]),
+                          record("FOO", vec![field("Bar", EfiType::UInt8)])],
+            aliases: vec![(String::from("FOO_PROTOCOL"), EfiType::UInt64)],
+            ..EfiModule::default()
+        };
+        let source = generate(&module, &RustOptions::default());
+        assert!(source.contains("pub struct Foo {"));
+        assert!(source.contains("pub struct Foo2 {"));
+        assert!(source.contains("pub type Foo3 = u64;"));
+
+        let collisions = check_collisions(&module).unwrap_err();
+        assert_eq!(collisions,
+                   vec![(String::from("EFI_FOO"), String::from("FOO")),
+                        (String::from("EFI_FOO"), String::from("FOO_PROTOCOL"))]);
+    }
+
+    fn arg(name: &str, ty: EfiType, dir: EfiArgDir) -> EfiArg {
+        EfiArg {
+            name: String::from(name),
+            ty: ty,
+            dir: dir,
+            optional: false,
+            restrict: false,
+            length_of: None,
+        }
+    }
+
+    /// A module with the protocol `EFI_FOO_PROTOCOL` and its single method `Reset`, which takes
+    /// `args` after `This`.
+    fn foo_protocol(args: Vec<EfiArg>) -> EfiModule {
+        let this = EfiType::Ptr(Box::new(EfiType::Id(String::from("EFI_FOO_PROTOCOL"))));
+        let mut method = EfiMethod {
+            name: String::from("Reset"),
+            ty: EfiType::Status,
+            args: vec![arg("This", this, EfiArgDir::In)],
+            abi: EfiAbi::Efiapi,
+            deprecated: false,
+            doc: None,
+            typedef: None,
+            const_ptr: false,
+            slices: Vec::new(),
+        };
+        method.args.extend(args);
+        EfiModule {
+            protocols: vec![EfiProtocol {
+                                name: String::from("EFI_FOO_PROTOCOL"),
+                                methods: vec![method],
+                                fields: Vec::new(),
+                                deprecated: false,
+                                guid: None,
+                                opaque: false,
+                            }],
+            ..EfiModule::default()
+        }
+    }
+
+    #[test]
+    fn deprecated_protocols_and_methods() {
+        let mut module = foo_protocol(Vec::new());
+        module.protocols[0].deprecated = true;
+        module.protocols[0].methods[0].deprecated = true;
+        let read = EfiMethod {
+            name: String::from("Read"),
+            deprecated: false,
+            ..module.protocols[0].methods[0].clone()
+        };
+        module.protocols[0].methods.push(read);
+
+        let source = generate(&module, &RustOptions::default());
+        let lines: Vec<_> = source.lines().map(|l| l.trim()).collect();
+        let before = |item: &str| {
+            let i = lines.iter().position(|l| l.starts_with(item)).expect(item);
+            lines[i - 1] == "#[deprecated]"
+        };
+        assert!(before("pub struct Foo {"));
+        assert!(before("pub trait FooProtocol {"));
+        assert!(before("fn reset(self)"));
+        assert!(!before("fn read(self)"));
+    }
+
+    #[test]
+    fn shared_method_typedef_is_one_alias() {
+        let mut module = foo_protocol(Vec::new());
+        let mut read = module.protocols[0].methods[0].clone();
+        read.typedef = Some(String::from("EFI_READ"));
+        module.callbacks.push(EfiMethod { name: String::from("EFI_READ"), ..read.clone() });
+        module.protocols[0].methods.push(EfiMethod { name: String::from("Read"), ..read.clone() });
+        module.protocols[0].methods.push(EfiMethod { name: String::from("ReadAgain"), ..read });
+
+        let source = generate(&module, &RustOptions::default());
+        assert_eq!(source.matches("pub type Read = unsafe extern \"win64\" fn(").count(),
+                   1,
+                   "{}",
+                   source);
+        assert!(source.contains("read: ::std::option::Option<Read>,"), "{}", source);
+        assert!(source.contains("read_again: ::std::option::Option<Read>,"), "{}", source);
+        // without a typedef of its own the method spells out its type
+        assert!(source.contains("reset: ::std::option::Option<unsafe extern \"win64\" fn("),
+                "{}",
+                source);
+        if let Err(e) = compile_check(&source) {
+            panic!("{}\n{}", source, e);
+        }
+    }
+
+    #[test]
+    fn only_const_pointees_give_const_pointers() {
+        let ptr = |ty| EfiType::Ptr(Box::new(ty));
+        let module = foo_protocol(vec![arg("Buffer", ptr(EfiType::UInt8), EfiArgDir::In),
+                                       arg("Name", EfiType::ConstPtr(Box::new(EfiType::Char16)),
+                                           EfiArgDir::In),
+                                       EfiArg {
+                                           optional: true,
+                                           ..arg("Key", ptr(EfiType::UInt32), EfiArgDir::In)
+                                       }]);
+        let source = generate(&module, &RustOptions::default());
+        let flat = source.split_whitespace().collect::<Vec<_>>().join(" ");
+
+        assert!(flat.contains("fn(this: *mut Foo, buffer: *mut u8, name: *const u16, \
+                               key: *mut u32)"),
+                "{}",
+                source);
+        assert!(source.contains("impl FooProtocol for *mut Foo {"), "{}", source);
+        assert!(source.contains("key.unwrap_or(::std::ptr::null_mut())"), "{}", source);
+        if let Err(e) = compile_check(&source) {
+            panic!("{}\n{}", source, e);
+        }
+    }
+
+    #[test]
+    fn receiver_is_found_by_position() {
+        let mut module = foo_protocol(vec![arg("value", EfiType::UInt32, EfiArgDir::In)]);
+        module.protocols[0].methods[0].args[0].name = String::from("this");
+
+        let source = generate(&module, &RustOptions::default());
+        assert!(source.contains("fn reset(self, value: u32)"), "{}", source);
+        assert!(source.contains("(self, value)"), "{}", source);
+    }
+
+    #[test]
+    fn sized_out_buffers_stay_parameters() {
+        let mut module = foo_protocol(vec![arg("BufferSize", EfiType::UIntN, EfiArgDir::In),
+                                           arg("Buffer",
+                                               EfiType::Ptr(Box::new(EfiType::UInt8)),
+                                               EfiArgDir::Out)]);
+        let source = generate(&module, &RustOptions::default());
+        assert!(source.contains("fn reset(self, buffer_size: usize) -> "), "{}", source);
+
+        module.protocols[0].methods[0].args[1].length_of = Some(2);
+        let source = generate(&module, &RustOptions::default());
+        assert!(source.contains("fn reset(self, buffer_size: usize, buffer: *mut u8) -> "),
+                "{}",
+                source);
+        assert!(!source.contains("zeroed"), "{}", source);
+    }
+
+    #[test]
+    fn error_check_keeps_the_status_width() {
+        let module = foo_protocol(Vec::new());
+        let options = |pointer_width| {
+            RustOptions {
+                pointer_width: pointer_width,
+                ..RustOptions::default()
+            }
+        };
+
+        let source = generate(&module, &options(Some(32)));
+        assert!(source.contains("if (status as i32) < 0"), "{}", source);
+        // what that check makes of an IA32 error code
+        let status = 0x8000_0001u32;
+        assert!((status as i32) < 0 && (status as i64) >= 0);
+
+        let source = generate(&module, &options(Some(64)));
+        assert!(source.contains("if (status as i64) < 0"), "{}", source);
+        let source = generate(&module, &options(None));
+        assert!(source.contains("if (status as isize) < 0"), "{}", source);
+    }
+
+    #[test]
+    fn missing_method_is_unsupported() {
+        let module = foo_protocol(Vec::new());
+        let options = |status_as, pointer_width| {
+            RustOptions {
+                status_as: status_as,
+                pointer_width: pointer_width,
+                ..RustOptions::default()
+            }
+        };
+
+        for &(status_as, pointer_width, unsupported) in
+            &[(StatusStyle::Uefi, Some(32), "2147483651"),
+              (StatusStyle::Uefi, Some(64), "9223372036854775811"),
+              (StatusStyle::Usize, None, "!(usize::max_value() >> 1) | 3"),
+              (StatusStyle::Newtype, Some(64), "Status(9223372036854775811)")] {
+            let source = generate(&module, &options(status_as, pointer_width));
+            // the arm may be broken across lines
+            let flat = source.split_whitespace().collect::<Vec<_>>().join(" ");
+            let unsupported = format!("None => return ::std::result::Result::Err({}),",
+                                      unsupported);
+            assert!(flat.contains(&unsupported), "{}", source);
+            assert!(!source.contains("expect"), "{}", source);
+            if let Err(e) = compile_check(&source) {
+                panic!("{}\n{}", source, e);
+            }
+        }
+    }
+
+    /// Compiles `source` as a library crate of its own, the error is what rustc reported.
+    fn compile_check(source: &str) -> Result<(), String> {
+        static COUNTER: AtomicUsize = ATOMIC_USIZE_INIT;
+
+        let count = COUNTER.fetch_add(1, Ordering::SeqCst);
+        let dir = env::temp_dir().join(format!("efi-bindgen-check-{}-{}", process::id(), count));
+        let path = dir.join("bindings.rs");
+        try!(fs::create_dir_all(&dir).map_err(|e| e.to_string()));
+        try!(File::create(&path)
+            .and_then(|mut f| f.write_all(source.as_bytes()))
+            .map_err(|e| e.to_string()));
+
+        let rustc = env::var("RUSTC").unwrap_or(String::from("rustc"));
+        let output = Command::new(rustc)
+            .args(&["--crate-type=lib", "--crate-name=bindings", "--emit=metadata"])
+            .args(&["--cap-lints=allow", "--out-dir"])
+            .arg(&dir)
+            .arg(&path)
+            .output();
+        let _ = fs::remove_dir_all(&dir);
+
+        let output = try!(output.map_err(|e| e.to_string()));
+        if output.status.success() {
+            Ok(())
+        } else {
+            Err(String::from_utf8_lossy(&output.stderr).into_owned())
+        }
+    }
+
+    #[test]
+    fn generated_protocol_compiles() {
+        let module = foo_protocol(vec![arg("Value", EfiType::UInt32, EfiArgDir::In)]);
+        let source = generate(&module, &RustOptions::default());
+        if let Err(e) = compile_check(&source) {
+            panic!("{}\n{}", source, e);
+        }
+    }
+
+    #[test]
+    fn array_lengths_reference_their_constant() {
+        let u16s = EfiType::Array(Box::new(EfiType::UInt16), 16);
+        let name = EfiField {
+            len_const: Some(String::from("EFI_NAME_LEN")),
+            ..field("Name", u16s.clone())
+        };
+        let module = EfiModule {
+            records: vec![record("EFI_FOO", vec![field("Label", u16s), name])],
+            constants: vec![EfiConstant {
+                                name: String::from("EFI_NAME_LEN"),
+                                value: EfiConstValue::Int(16),
+                            }],
+            ..EfiModule::default()
+        };
+        let source = generate(&module, &RustOptions::default());
+        assert!(source.contains("pub label: [u16; 16],"), "{}", source);
+        assert!(source.contains("pub name: [u16; consts::EFI_NAME_LEN as usize],"), "{}", source);
+        assert!(source.contains("pub const EFI_NAME_LEN: u64 = 16;"), "{}", source);
+        if let Err(e) = compile_check(&source) {
+            panic!("{}\n{}", source, e);
+        }
+    }
+
+    #[test]
+    fn void_pointer_styles() {
+        let module = EfiModule {
+            records: vec![record("EFI_FOO",
+                                 vec![field("Context", EfiType::Ptr(Box::new(EfiType::Void)))])],
+            ..EfiModule::default()
+        };
+        let options = |void_ptr_as| {
+            RustOptions {
+                void_ptr_as: void_ptr_as,
+                ..RustOptions::default()
+            }
+        };
+
+        let source = generate(&module, &options(VoidPtrStyle::CVoid));
+        assert!(source.contains("pub context: *mut ::std::os::raw::c_void,"), "{}", source);
+        let source = generate(&module, &options(VoidPtrStyle::U8));
+        assert!(source.contains("pub context: *mut u8,"), "{}", source);
+    }
+
+    #[test]
+    fn protocols_implement_the_guid_trait() {
+        let mut module = foo_protocol(Vec::new());
+        module.protocols[0].guid = Some(EfiGuid {
+            data1: 0x12345678,
+            data2: 0x9abc,
+            data3: 0xdef0,
+            data4: [1, 2, 3, 4, 5, 6, 7, 8],
+        });
+        let ptr = |ty| EfiType::Ptr(Box::new(ty));
+        module.callbacks.push(EfiMethod {
+            name: String::from("EFI_LOCATE_PROTOCOL"),
+            ty: EfiType::Status,
+            args: vec![arg("Protocol", ptr(EfiType::Guid), EfiArgDir::In),
+                       EfiArg {
+                           optional: true,
+                           ..arg("Registration", ptr(EfiType::Void), EfiArgDir::In)
+                       },
+                       arg("Interface", ptr(ptr(EfiType::Void)), EfiArgDir::Out)],
+            abi: EfiAbi::Efiapi,
+            deprecated: false,
+            doc: None,
+            typedef: None,
+            const_ptr: false,
+            slices: Vec::new(),
+        });
+        module.records.push(record("EFI_BOOT_SERVICES",
+                                   vec![field("LocateProtocol",
+                                              EfiType::Id(String::from("EFI_LOCATE_PROTOCOL")))]));
+        let options = RustOptions { emit_protocol_trait: true, ..RustOptions::default() };
+
+        let source = generate(&module, &options);
+        // the printer breaks associated constants across lines
+        let words = source.split_whitespace().collect::<Vec<_>>().join(" ");
+        assert!(words.contains("pub trait Protocol { const GUID: Guid; }"), "{}", source);
+        assert!(words.contains("impl Protocol for Foo { const GUID: Guid = Guid{data1: 305419896, \
+                                data2: 39612, data3: 57072, data4: [1, 2, 3, 4, 5, 6, 7, 8],}; }"),
+                "{}",
+                source);
+        assert!(words.contains("pub unsafe fn locate<P: Protocol>(bs: &BootServices)"),
+                "{}",
+                source);
+        if let Err(e) = compile_check(&source) {
+            panic!("{}\n{}", source, e);
+        }
+    }
+
+    #[test]
+    fn register_unions_keep_bitfields_and_the_raw_value() {
+        let bits = EfiType::Id(String::from("EFI_REGISTER_BITS"));
+        let mut register = record("EFI_REGISTER",
+                                  vec![field("Bits", bits), field("Uint32", EfiType::UInt32)]);
+        register.kind = EfiRecordKind::Union;
+        let module = EfiModule {
+            records: vec![register,
+                          record("EFI_REGISTER_BITS",
+                                 vec![bitfield("Enable", EfiType::UInt32, 1),
+                                      bitfield("Mode", EfiType::UInt32, 3)])],
+            ..EfiModule::default()
+        };
+        let source = generate(&module, &RustOptions::default());
+
+        assert!(source.contains("pub union Register {\n    pub bits: RegisterBits,\n    \
+                                 pub uint32: u32,\n}"),
+                "{}",
+                source);
+        assert!(source.contains("Bitfields `Enable`: bit 0, `Mode`: bits 1-3"), "{}", source);
+        if let Err(e) = compile_check(&source) {
+            panic!("{}\n{}", source, e);
+        }
+    }
+}
@@ -0,0 +1,36 @@
+//! Generates Rust bindings for UEFI protocol headers.
+//!
+//! From a build script:
+//!
+//! ```ignore
+//! efi_bindgen::Builder::new()
+//!     .header("Include/Protocol/BlockIo.h")
+//!     .clang_arg("-IInclude")
+//!     .generate()
+//!     .unwrap()
+//!     .write_to_file(Path::new(&env::var("OUT_DIR").unwrap()).join("block_io.rs"))
+//!     .unwrap();
+//! ```
+
+#![feature(rustc_private)]
+#![cfg_attr(feature = "serialize", feature(proc_macro))]
+
+extern crate clang;
+extern crate glob;
+extern crate syntax;
+#[cfg(feature = "serialize")]
+extern crate serde;
+#[cfg(feature = "serialize")]
+#[macro_use]
+extern crate serde_derive;
+#[cfg(feature = "serialize")]
+extern crate serde_json;
+
+pub mod types;
+pub mod error;
+mod macros;
+pub mod parser;
+pub mod gen;
+pub mod visit;
+
+pub use gen::{Bindings, Builder};
@@ -0,0 +1,308 @@
+use types::EfiGuid;
+
+/// Nesting of parentheses, casts and unary operators past which an expression is rejected
+/// rather than risking a stack overflow.
+const MAX_DEPTH: usize = 256;
+
+struct Eval<'a, F: 'a> {
+    tokens: &'a [String],
+    pos: usize,
+    depth: usize,
+    lookup: &'a F,
+}
+
+fn parse_int(lit: &str) -> Option<u64> {
+    let lit = lit.trim_right_matches(|c| c == 'u' || c == 'U' || c == 'l' || c == 'L');
+
+    if lit.starts_with("0x") || lit.starts_with("0X") {
+        u64::from_str_radix(&lit[2..], 16).ok()
+    } else if lit.len() > 1 && lit.starts_with('0') {
+        u64::from_str_radix(&lit[1..], 8).ok()
+    } else {
+        lit.parse().ok()
+    }
+}
+
+fn is_ident(tok: &str) -> bool {
+    tok.chars().next().map_or(false, |c| c.is_alphabetic() || c == '_')
+}
+
+fn precedence(op: &str) -> Option<u32> {
+    match op {
+        "|" => Some(1),
+        "^" => Some(2),
+        "&" => Some(3),
+        "<<" | ">>" => Some(4),
+        "+" | "-" => Some(5),
+        "*" | "/" | "%" => Some(6),
+        _ => None,
+    }
+}
+
+impl<'a, F> Eval<'a, F>
+    where F: Fn(&str) -> Option<u64>
+{
+    fn peek(&self) -> Option<&'a str> {
+        self.tokens.get(self.pos).map(|t| t.as_ref())
+    }
+
+    fn next(&mut self) -> Option<&'a str> {
+        let tok = self.peek();
+        self.pos += 1;
+        tok
+    }
+
+    fn primary(&mut self) -> Option<u64> {
+        if self.depth == MAX_DEPTH {
+            return None;
+        }
+        self.depth += 1;
+        let val = self.operand();
+        self.depth -= 1;
+        val
+    }
+
+    fn operand(&mut self) -> Option<u64> {
+        match self.next() {
+            Some("(") => {
+                // a parenthesized unknown identifier is a cast, e.g. `(UINT64) 1`
+                let cast = self.peek()
+                    .map_or(false, |t| is_ident(t) && (self.lookup)(t).is_none()) &&
+                           self.tokens.get(self.pos + 1).map_or(false, |t| t == ")");
+                if cast {
+                    self.pos += 2;
+                    return self.primary();
+                }
+
+                let val = self.expr(0);
+                match self.next() {
+                    Some(")") => val,
+                    _ => None,
+                }
+            }
+            Some("-") => self.primary().map(|v| v.wrapping_neg()),
+            Some("~") => self.primary().map(|v| !v),
+            Some("+") => self.primary(),
+            Some(tok) if is_ident(tok) => (self.lookup)(tok),
+            Some(tok) => parse_int(tok),
+            None => None,
+        }
+    }
+
+    fn expr(&mut self, min_prec: u32) -> Option<u64> {
+        let mut lhs = match self.primary() {
+            Some(val) => val,
+            None => return None,
+        };
+
+        while let Some(op) = self.peek() {
+            let prec = match precedence(op) {
+                Some(prec) if prec > min_prec => prec,
+                _ => break,
+            };
+            self.pos += 1;
+            let rhs = match self.expr(prec) {
+                Some(val) => val,
+                None => return None,
+            };
+
+            lhs = match op {
+                "|" => lhs | rhs,
+                "^" => lhs ^ rhs,
+                "&" => lhs & rhs,
+                "<<" => lhs.wrapping_shl(rhs as u32),
+                ">>" => lhs.wrapping_shr(rhs as u32),
+                "+" => lhs.wrapping_add(rhs),
+                "-" => lhs.wrapping_sub(rhs),
+                "*" => lhs.wrapping_mul(rhs),
+                "/" if rhs != 0 => lhs / rhs,
+                "%" if rhs != 0 => lhs % rhs,
+                _ => return None,
+            };
+        }
+
+        Some(lhs)
+    }
+}
+
+/// Evaluates the body of an object-like macro as an integer constant expression,
+/// e.g. `((2 << 16) | (70))`. Identifiers are resolved through `lookup`.
+pub fn eval_int<F>(tokens: &[String], lookup: &F) -> Option<u64>
+    where F: Fn(&str) -> Option<u64>
+{
+    let mut eval = Eval {
+        tokens: tokens,
+        pos: 0,
+        depth: 0,
+        lookup: lookup,
+    };
+
+    match eval.expr(0) {
+        Some(val) if eval.pos == tokens.len() => Some(val),
+        _ => None,
+    }
+}
+
+/// Parses the registry format of a GUID, `964e5b21-6459-11d2-8e39-00a0c969723b`, optionally in
+/// braces.
+fn parse_guid_string(s: &str) -> Option<EfiGuid> {
+    let s = if s.starts_with('{') && s.ends_with('}') { &s[1..s.len() - 1] } else { s };
+    let parts: Vec<_> = s.split('-').collect();
+    let lengths = [8, 4, 4, 4, 12];
+    let is_group = |p: &str, len: usize| p.len() == len && p.chars().all(|c| c.is_digit(16));
+    if parts.len() != lengths.len() || !parts.iter().zip(&lengths).all(|(p, &l)| is_group(p, l)) {
+        return None;
+    }
+
+    let hex = |s: &str| u64::from_str_radix(s, 16).unwrap();
+    let bytes = format!("{}{}", parts[3], parts[4]);
+    let mut data4 = [0; 8];
+    for (i, b) in data4.iter_mut().enumerate() {
+        *b = hex(&bytes[2 * i..2 * i + 2]) as u8;
+    }
+
+    Some(EfiGuid {
+        data1: hex(parts[0]) as u32,
+        data2: hex(parts[1]) as u16,
+        data3: hex(parts[2]) as u16,
+        data4: data4,
+    })
+}
+
+/// Evaluates a GUID initializer such as
+/// `{ 0x964e5b21, 0x6459, 0x11d2, { 0x8e, 0x39, 0x0, 0xa0, 0xc9, 0x69, 0x72, 0x3b } }`, or a
+/// string literal of its registry format, `"964e5b21-6459-11d2-8e39-00a0c969723b"`.
+pub fn eval_guid(tokens: &[String]) -> Option<EfiGuid> {
+    if tokens.len() == 1 && tokens[0].len() >= 2 && tokens[0].starts_with('"') &&
+       tokens[0].ends_with('"') {
+        return parse_guid_string(&tokens[0][1..tokens[0].len() - 1]);
+    }
+    if tokens.first().map_or(true, |t| t != "{") || tokens.last().map_or(true, |t| t != "}") {
+        return None;
+    }
+
+    let mut values = Vec::new();
+    for tok in tokens {
+        match tok.as_ref() {
+            "{" | "}" | "," => {}
+            tok => {
+                match parse_int(tok) {
+                    Some(val) => values.push(val),
+                    None => return None,
+                }
+            }
+        }
+    }
+
+    if values.len() != 11 || values[0] > 0xffffffff || values[1..3].iter().any(|&v| v > 0xffff) ||
+       values[3..].iter().any(|&v| v > 0xff) {
+        return None;
+    }
+
+    let mut data4 = [0; 8];
+    for (b, &v) in data4.iter_mut().zip(&values[3..]) {
+        *b = v as u8;
+    }
+
+    Some(EfiGuid {
+        data1: values[0] as u32,
+        data2: values[1] as u16,
+        data3: values[2] as u16,
+        data4: data4,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::iter;
+
+    /// `parts` repeated `n` times.
+    fn repeat(parts: &[&str], n: usize) -> Vec<String> {
+        iter::repeat(parts).take(n).flat_map(|p| p.iter().map(|t| String::from(*t))).collect()
+    }
+
+    fn concat(parts: &[Vec<String>]) -> Vec<String> {
+        parts.iter().flat_map(|p| p.iter().cloned()).collect()
+    }
+
+    /// Splits at spaces, the tests spell every token apart.
+    fn tokens(source: &str) -> Vec<String> {
+        source.split_whitespace().map(String::from).collect()
+    }
+
+    #[test]
+    fn eval_int_expressions() {
+        let lookup = |name: &str| if name == "EFI_BASE" { Some(0x10) } else { None };
+        let eval = |source| eval_int(&tokens(source), &lookup);
+        assert_eq!(eval("( ( 2 << 16 ) | ( 70 ) )"), Some(0x20046));
+        assert_eq!(eval("1 + 2 * 3"), Some(7));
+        assert_eq!(eval("8 - 4 - 2"), Some(2));
+        assert_eq!(eval("EFI_BASE + 0x1ULL"), Some(0x11));
+        assert_eq!(eval("( UINT64 ) 010"), Some(8));
+        assert_eq!(eval("~ 0"), Some(!0));
+        assert_eq!(eval("- 1"), Some(!0));
+        assert_eq!(eval("1 / 0"), None);
+        assert_eq!(eval("EFI_UNKNOWN"), None);
+        assert_eq!(eval("1 2"), None);
+        assert_eq!(eval("( 1"), None);
+    }
+
+    #[test]
+    fn eval_guid_initializers() {
+        let guid = "{ 0x964e5b21 , 0x6459 , 0x11d2 , \
+                    { 0x8e , 0x39 , 0x0 , 0xa0 , 0xc9 , 0x69 , 0x72 , 0x3b } }";
+        assert_eq!(eval_guid(&tokens(guid)),
+                   Some(EfiGuid {
+                       data1: 0x964e5b21,
+                       data2: 0x6459,
+                       data3: 0x11d2,
+                       data4: [0x8e, 0x39, 0x00, 0xa0, 0xc9, 0x69, 0x72, 0x3b],
+                   }));
+
+        // too few values, a value too large for its field, not braced
+        assert_eq!(eval_guid(&tokens("{ 0x1 , 0x2 , 0x3 , { 0x4 } }")), None);
+        assert_eq!(eval_guid(&tokens(&guid.replace("0x6459", "0x16459"))), None);
+        assert_eq!(eval_guid(&tokens(&guid[1..guid.len() - 1])), None);
+    }
+
+    #[test]
+    fn eval_guid_strings() {
+        let braced = "{ 0x964e5b21 , 0x6459 , 0x11d2 , \
+                      { 0x8e , 0x39 , 0x0 , 0xa0 , 0xc9 , 0x69 , 0x72 , 0x3b } }";
+        let guid = eval_guid(&tokens(braced));
+        assert!(guid.is_some());
+        assert_eq!(eval_guid(&tokens("\"964e5b21-6459-11d2-8e39-00a0c969723b\"")), guid);
+        assert_eq!(eval_guid(&tokens("\"{964E5B21-6459-11D2-8E39-00A0C969723B}\"")), guid);
+
+        // a missing group, a group too long, not hex, not a string
+        assert_eq!(eval_guid(&tokens("\"964e5b21-6459-11d2-00a0c969723b\"")), None);
+        assert_eq!(eval_guid(&tokens("\"964e5b21-64590-11d2-8e39-00a0c969723b\"")), None);
+        assert_eq!(eval_guid(&tokens("\"964e5b21-6459-11d2-8e39-00a0c969723g\"")), None);
+        assert_eq!(eval_guid(&tokens("964e5b21-6459-11d2-8e39-00a0c969723b")), None);
+    }
+
+    #[test]
+    fn deep_parentheses_are_rejected() {
+        let none = |_: &str| None;
+        let parens = |n| concat(&[repeat(&["("], n), repeat(&["1"], 1), repeat(&[")"], n)]);
+        assert_eq!(eval_int(&parens(100), &none), Some(1));
+        assert_eq!(eval_int(&parens(100000), &none), None);
+    }
+
+    #[test]
+    fn deep_unary_operators_are_rejected() {
+        let none = |_: &str| None;
+        let negations = |n| concat(&[repeat(&["-"], n), repeat(&["1"], 1)]);
+        assert_eq!(eval_int(&negations(100), &none), Some(1));
+        assert_eq!(eval_int(&negations(100000), &none), None);
+    }
+
+    #[test]
+    fn deep_casts_are_rejected() {
+        let none = |_: &str| None;
+        let casts = |n| concat(&[repeat(&["(", "UINT64", ")"], n), repeat(&["1"], 1)]);
+        assert_eq!(eval_int(&casts(100), &none), Some(1));
+        assert_eq!(eval_int(&casts(100000), &none), None);
+    }
+}
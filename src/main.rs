@@ -1,19 +1,24 @@
-#![feature(rustc_private)]
+extern crate efi_bindgen;
 
-extern crate clang;
-extern crate syntax;
-
-use std::iter::Iterator;
-use parser::parse;
-use gen::gen_module;
-
-mod types;
-mod parser;
-mod gen;
+use std::io;
+use std::io::Write;
+use efi_bindgen::Builder;
+use efi_bindgen::gen::check_collisions;
 
 fn main() {
     let efi_header = std::env::args().nth(1).expect("No EFI header specified!");
-    if let Err(e) = parse(&efi_header).and_then(|module| gen_module(&module).map_err(Box::from)) {
-        println!("{}", e.description())
+    let bindings = match Builder::new().header(efi_header).generate() {
+        Ok(bindings) => bindings,
+        Err(e) => return println!("{}", e),
+    };
+
+    for warning in &bindings.module().warnings {
+        let _ = writeln!(io::stderr(), "warning: {}", warning);
+    }
+    if let Err(collisions) = check_collisions(bindings.module()) {
+        for (a, b) in collisions {
+            let _ = writeln!(io::stderr(), "warning: {} and {} map to the same Rust name", a, b);
+        }
     }
+    print!("{}", bindings.as_str());
 }
@@ -1,12 +1,29 @@
 use clang::*;
+use glob::glob;
+use clang::token::Token;
 use std::io;
 use std::io::prelude::*;
 use std::path::{Path, PathBuf};
-use std::fs::{canonicalize, File};
-use std::error::Error;
+use std::env;
+use std::fs::{self, canonicalize, OpenOptions};
+use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+use std::time::{SystemTime, UNIX_EPOCH};
+use std::collections::HashMap;
+use std::fmt;
+use std::rc::Rc;
 use std::iter::Iterator;
+#[cfg(feature = "serialize")]
+use serde_json;
+use macros::{eval_guid, eval_int};
+use error::ParseError;
 use types::*;
 
+/// Types the template defines as marker structs, see `to_marker_type`.
+const MARKER_TYPEDEFS: &'static [&'static str] = &["EFI_STATUS", "UINTN", "INTN", "BOOLEAN",
+                                                   "INT8", "UINT8", "INT16", "UINT16", "INT32",
+                                                   "UINT32", "INT64", "UINT64", "CHAR8",
+                                                   "CHAR16", "EFI_GUID"];
+
 trait Check<T> {
     fn and_check<F>(self, f: F) -> Option<T> where F: FnOnce(&T) -> bool;
 }
@@ -22,46 +39,182 @@ impl<T> Check<T> for Option<T> {
     }
 }
 
+
+/// Selects the source files that contribute declarations to the module.
+#[derive(Clone, Debug)]
+pub enum IncludeFilter {
+    /// Only the header passed to `parse`, or the ones passed to `parse_many`.
+    ExactFile,
+    /// The headers and any file below one of the given directories.
+    ProjectHeaders(Vec<PathBuf>),
+    /// Every file that is not a system header.
+    All,
+}
+
+impl IncludeFilter {
+    fn accepts(&self, entity: &Entity, efi_headers: &[PathBuf], aux_header: &Path) -> bool {
+        let path = match entity.get_location() {
+            Some(loc) => loc.get_file_location().file.get_path(),
+            None => return false,
+        };
+
+        if efi_headers.contains(&path) {
+            return true;
+        }
+
+        match *self {
+            IncludeFilter::ExactFile => false,
+            IncludeFilter::ProjectHeaders(ref dirs) => dirs.iter().any(|d| path.starts_with(d)),
+            IncludeFilter::All => path != aux_header && !entity.is_in_system_header(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct ParseOptions {
+    pub include_filter: IncludeFilter,
+    /// Type names defined outside the parsed headers, mapped to the Rust path
+    /// generators should reference them by.
+    pub extern_types: HashMap<String, String>,
+    /// Aborts parsing once more entities than this have been visited, guarding against
+    /// runaway inputs.
+    pub max_entities: Option<usize>,
+    /// Fields whose name starts with one of these, ignoring case and leading underscores,
+    /// are marked reserved.
+    pub reserved_patterns: Vec<String>,
+    /// Collects functions defined in the headers, e.g. `static inline` helpers.
+    pub capture_inline_fns: bool,
+    /// Transforms method argument names as they are read, e.g. to strip prefixes.
+    pub arg_name_fn: Option<Rc<Fn(&str) -> String>>,
+    /// Ignores headers that `#define` the primitive types instead of failing, and skips
+    /// declarations that cannot be mapped, recording them in `EfiModule::warnings`.
+    pub lenient: bool,
+    /// Passed to clang after the default arguments, e.g. `-I` or `-D` flags.
+    pub clang_args: Vec<String>,
+    /// Loads the module from the header's `sidecar_path` instead of running clang, if that
+    /// file exists. Only available with the `serialize` feature, without it the sidecar is
+    /// never looked for.
+    pub prefer_sidecar: bool,
+}
+
+impl fmt::Debug for ParseOptions {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ParseOptions")
+            .field("include_filter", &self.include_filter)
+            .field("extern_types", &self.extern_types)
+            .field("max_entities", &self.max_entities)
+            .field("reserved_patterns", &self.reserved_patterns)
+            .field("capture_inline_fns", &self.capture_inline_fns)
+            .field("arg_name_fn", &self.arg_name_fn.as_ref().map(|_| "Fn"))
+            .field("lenient", &self.lenient)
+            .field("clang_args", &self.clang_args)
+            .field("prefer_sidecar", &self.prefer_sidecar)
+            .finish()
+    }
+}
+
+impl Default for ParseOptions {
+    fn default() -> ParseOptions {
+        ParseOptions {
+            include_filter: IncludeFilter::ExactFile,
+            extern_types: HashMap::new(),
+            max_entities: None,
+            reserved_patterns: vec!["Reserved".to_string(), "Pad".to_string()],
+            capture_inline_fns: false,
+            arg_name_fn: None,
+            lenient: false,
+            clang_args: Vec::new(),
+            prefer_sidecar: false,
+        }
+    }
+}
+
 fn type_name(ty: &Type) -> String {
+    // anonymous records such as `typedef union { ... } EFI_FOO;` are only known by the
+    // typedef name, which clang uses as their display name
     String::from(ty.get_declaration()
+        .and_check(|d| !d.is_anonymous())
         .and_then(|d| d.get_name())
         .unwrap_or_else(|| ty.get_display_name())
         .trim_left_matches('_'))
 }
 
-fn to_efi_type(ty: &Type) -> Result<EfiType, String> {
+fn to_marker_type(name: &str) -> Option<EfiType> {
+    match name {
+        "efi_status" => Some(EfiType::Status),
+        "efi_uintn" => Some(EfiType::UIntN),
+        "efi_intn" => Some(EfiType::IntN),
+        "efi_bool" => Some(EfiType::Bool),
+        "efi_int8" => Some(EfiType::Int8),
+        "efi_uint8" => Some(EfiType::UInt8),
+        "efi_int16" => Some(EfiType::Int16),
+        "efi_uint16" => Some(EfiType::UInt16),
+        "efi_int32" => Some(EfiType::Int32),
+        "efi_uint32" => Some(EfiType::UInt32),
+        "efi_int64" => Some(EfiType::Int64),
+        "efi_uint64" => Some(EfiType::UInt64),
+        "efi_char8" => Some(EfiType::Char8),
+        "efi_char16" => Some(EfiType::Char16),
+        "efi_guid" => Some(EfiType::Guid),
+        // spec typedefs the headers define themselves, kept for the intent they carry
+        "EFI_STRING" => Some(EfiType::Ucs2Ptr),
+        "EFI_STRING_ID" => Some(EfiType::StringId),
+        _ => None,
+    }
+}
+
+fn to_efi_type(ty: &Type) -> Result<EfiType, ParseError> {
     match ty.get_kind() {
         TypeKind::Record => {
             let name = type_name(ty);
-            match name.as_ref() {
-                "efi_status" => Ok(EfiType::Status),
-                "efi_uintn" => Ok(EfiType::UIntN),
-                "efi_intn" => Ok(EfiType::IntN),
-                "efi_bool" => Ok(EfiType::Bool),
-                "efi_int8" => Ok(EfiType::Int8),
-                "efi_uint8" => Ok(EfiType::UInt8),
-                "efi_int16" => Ok(EfiType::Int16),
-                "efi_uint16" => Ok(EfiType::UInt16),
-                "efi_int32" => Ok(EfiType::Int32),
-                "efi_uint32" => Ok(EfiType::UInt32),
-                "efi_int64" => Ok(EfiType::Int64),
-                "efi_uint64" => Ok(EfiType::UInt64),
-                "efi_char8" => Ok(EfiType::Char8),
-                "efi_char16" => Ok(EfiType::Char16),
-                _ => Ok(EfiType::Id(name)),
-            }
+            Ok(to_marker_type(&name).unwrap_or(EfiType::Id(name)))
         }
+        TypeKind::Void => Ok(EfiType::Void),
+        TypeKind::Float => Ok(EfiType::Float32),
+        TypeKind::Double => Ok(EfiType::Float64),
         TypeKind::Enum => Ok(EfiType::Id(type_name(ty))),
+        TypeKind::ConstantArray => {
+            let elem = &try!(ty.get_element_type()
+                .ok_or(ParseError::MissingField("array without element type")));
+            let len = try!(ty.get_size().ok_or(ParseError::MissingField("array without size")));
+            Ok(EfiType::Array(Box::new(try!(to_efi_type(elem))), len))
+        }
+        TypeKind::IncompleteArray => {
+            let elem = &try!(ty.get_element_type()
+                .ok_or(ParseError::MissingField("array without element type")));
+            Ok(EfiType::Array(Box::new(try!(to_efi_type(elem))), 0))
+        }
         TypeKind::Pointer => {
-            let pointee = &try!(ty.get_pointee_type().ok_or("pointer has not pointee type"));
-            let ty = try!(to_efi_type(pointee));
-            Ok(EfiType::Ptr(Box::new(ty)))
+            let pointee = &try!(ty.get_pointee_type()
+                .ok_or(ParseError::MissingField("pointer has not pointee type")));
+            let ty = Box::new(try!(to_efi_type(pointee)));
+            if pointee.is_const_qualified() {
+                Ok(EfiType::ConstPtr(ty))
+            } else {
+                Ok(EfiType::Ptr(ty))
+            }
         }
         TypeKind::Typedef => {
+            // markers may also be typedefs of builtin types, which canonicalizing would lose
+            let mut alias = *ty;
+            loop {
+                let decl = match alias.get_declaration() {
+                    Some(decl) => decl,
+                    None => break,
+                };
+                if let Some(ty) = decl.get_name().and_then(|n| to_marker_type(&n)) {
+                    return Ok(ty);
+                }
+                match decl.get_typedef_underlying_type() {
+                    Some(ty) if ty.get_kind() == TypeKind::Typedef => alias = ty,
+                    _ => break,
+                }
+            }
+
             let cty = &ty.get_canonical_type();
             Ok(to_efi_type(cty).unwrap_or_else(|_| EfiType::Id(type_name(ty))))
         }
-        _ => Err(String::from(format!("unsupported type {:?}", ty))),
+        _ => Err(ParseError::UnsupportedType { name: ty.get_display_name() }),
     }
 }
 
@@ -83,16 +236,55 @@ fn to_efi_argopt(ty: &Type) -> bool {
         .is_some()
 }
 
-fn to_efi_method(ty: &Type) -> Result<EfiMethod, String> {
-    let res = &try!(ty.get_result_type().ok_or("function prototype without result"));
-    let args = try!(ty.get_argument_types().ok_or("function prototype without args"));
+fn to_efi_argtype(ty: &Type) -> Result<EfiType, ParseError> {
+    // array parameters decay to pointers, but libclang may still report the
+    // type as written or as an unexposed decayed type
+    match ty.get_kind() {
+        TypeKind::ConstantArray |
+        TypeKind::IncompleteArray |
+        TypeKind::VariableArray => {
+            let elem = &try!(ty.get_element_type()
+                .ok_or(ParseError::MissingField("array without element type")));
+            let ty = Box::new(try!(to_efi_type(elem)));
+            if elem.is_const_qualified() {
+                Ok(EfiType::ConstPtr(ty))
+            } else {
+                Ok(EfiType::Ptr(ty))
+            }
+        }
+        // only the pointer is canonical here, `decayed_element_type` restores the element type
+        TypeKind::Unexposed if ty.get_canonical_type().get_kind() == TypeKind::Pointer => {
+            to_efi_type(&ty.get_canonical_type())
+        }
+        // typedefs of array types decay as well
+        _ => {
+            match try!(to_efi_type(ty)) {
+                EfiType::Array(elem, _) => Ok(EfiType::Ptr(elem)),
+                ty => Ok(ty),
+            }
+        }
+    }
+}
+
+fn to_efi_method(ty: &Type) -> Result<EfiMethod, ParseError> {
+    let res = &try!(ty.get_result_type()
+        .ok_or(ParseError::MissingField("function prototype without result")));
+    let args = try!(ty.get_argument_types()
+        .ok_or(ParseError::MissingField("function prototype without args")));
 
     let mut efi_args: Vec<EfiArg> = Vec::new();
     let mut dir = EfiArgDir::In;
+    let mut after_marker = false;
 
     for ref arg in args {
         if let Some(new_dir) = to_efi_argdir(arg) {
-            dir = new_dir;
+            // `IN OUT` shows up as two consecutive markers
+            dir = if after_marker && dir == EfiArgDir::In && new_dir == EfiArgDir::Out {
+                EfiArgDir::InOut
+            } else {
+                new_dir
+            };
+            after_marker = true;
             continue;
         }
 
@@ -103,27 +295,82 @@ fn to_efi_method(ty: &Type) -> Result<EfiMethod, String> {
             continue;
         }
 
+        // the qualifier of an array parameter ends up on the pointer it decays to
+        let restrict = arg.is_restrict_qualified() ||
+                       arg.get_canonical_type().is_restrict_qualified();
         efi_args.push(EfiArg {
             name: String::new(),
-            ty: try!(to_efi_type(arg)),
+            ty: try!(to_efi_argtype(arg)),
             dir: dir,
             optional: false,
+            restrict: restrict,
+            length_of: None,
         });
+        after_marker = false;
     }
 
     Ok(EfiMethod {
         name: String::new(),
         ty: try!(to_efi_type(res)),
         args: efi_args,
+        abi: match ty.get_calling_convention() {
+            Some(CallingConvention::Win64) => EfiAbi::Efiapi,
+            _ => EfiAbi::C,
+        },
+        deprecated: false,
+        doc: None,
+        typedef: None,
+        const_ptr: false,
+        slices: Vec::new(),
     })
 }
 
-fn process_enum_decls(entity: &Entity, enm: &mut EfiEnum) -> Result<(), String> {
+fn is_efiapi(entity: &Entity) -> bool {
+    entity.get_children().iter().any(|c| {
+        c.get_kind() == EntityKind::AnnotateAttr &&
+        c.get_display_name().map_or(false, |n| n == "efi_fun_decl")
+    })
+}
+
+fn is_ms_struct(entity: &Entity) -> bool {
+    // libclang does not expose the attribute, only its spelling
+    entity.get_children().iter().any(|c| {
+        c.get_kind() == EntityKind::UnexposedAttr &&
+        c.get_range()
+            .map_or(false, |r| r.tokenize().iter().any(|t| t.get_spelling() == "ms_struct"))
+    })
+}
+
+fn is_deprecated(entity: &Entity) -> bool {
+    entity.get_availability() == Availability::Deprecated
+}
+
+/// The comment attached to `entity` with the comment markers stripped from each line.
+fn doc_comment(entity: &Entity) -> Option<String> {
+    entity.get_comment().map(|comment| {
+        let lines: Vec<_> = comment.lines()
+            .map(|l| {
+                l.trim()
+                    .trim_right_matches("*/")
+                    .trim_left_matches(|c| c == '/' || c == '*' || c == '!')
+                    .trim_left_matches('<')
+                    .trim()
+            })
+            .collect();
+        let start = lines.iter().position(|l| !l.is_empty()).unwrap_or(lines.len());
+        let end = lines.iter().rposition(|l| !l.is_empty()).map_or(start, |i| i + 1);
+        lines[start..end].join("\n")
+    })
+}
+
+fn process_enum_decls(entity: &Entity, enm: &mut EfiEnum) -> Result<(), ParseError> {
     if entity.get_kind() == EntityKind::EnumConstantDecl {
         enm.fields.push(EfiVariant {
-            name: try!(entity.get_name().ok_or("enum variant has no name")),
+            name: try!(entity.get_name()
+                .ok_or(ParseError::MissingField("enum variant has no name"))),
             value: match entity.get_enum_constant_value() {
                 None => None,
+                Some((val, _)) if enm.signed => Some(val as u64),
                 Some((_, val)) => Some(val),
             },
         });
@@ -136,105 +383,426 @@ fn process_enum_decls(entity: &Entity, enm: &mut EfiEnum) -> Result<(), String>
     Ok(())
 }
 
-fn process_typedef(entity: &Entity, module: &mut EfiModule) -> Result<(), String> {
-    let name = try!(entity.get_name().ok_or("typedef without name"));
+/// Whether the record type has no name of its own, e.g. an inline `union { ... }` member. A
+/// typedef of an anonymous record such as `EFI_IP_ADDRESS` names it.
+fn is_anonymous_record(ty: &Type) -> bool {
+    let cty = ty.get_canonical_type();
+    ty.get_kind() != TypeKind::Typedef && cty.get_kind() == TypeKind::Record &&
+    cty.get_declaration().map_or(false, |d| d.is_anonymous() || d.get_name().is_none())
+}
+
+/// The identifier between the brackets of an array declaration such as `CHAR16 Name[NAME_LEN]`,
+/// `None` if the length is spelled as a literal or an expression.
+fn array_len_const(field: &Entity) -> Option<String> {
+    let range = match field.get_range() {
+        Some(range) => range,
+        None => return None,
+    };
+    // see `macro_body`, the tokens may run past the end of the range
+    let end = range.get_end().get_file_location().offset;
+    let tokens: Vec<_> = range.tokenize()
+        .iter()
+        .filter(|t| t.get_range().get_start().get_file_location().offset < end)
+        .map(|t| t.get_spelling())
+        .collect();
+
+    let open = match tokens.iter().position(|t| *t == "[") {
+        Some(open) => open,
+        None => return None,
+    };
+    let is_ident = |t: &str| {
+        t.starts_with(|c: char| c.is_alphabetic() || c == '_') &&
+        t.chars().all(|c| c.is_alphanumeric() || c == '_')
+    };
+    if tokens.len() == open + 3 && is_ident(&tokens[open + 1]) && tokens[open + 2] == "]" {
+        Some(tokens[open + 1].clone())
+    } else {
+        None
+    }
+}
+
+/// Converts a record type. Anonymous records nested inside are synthesized as records of their
+/// own, named after `name` and the member, and added to `module`.
+fn to_efi_record(name: &str, ty: &Type, module: &mut EfiModule) -> Result<EfiRecord, ParseError> {
+    let cty = ty.get_canonical_type();
+    let fields = try!(cty.get_fields()
+        .ok_or(ParseError::UnsupportedType { name: ty.get_display_name() }));
+    let mut efi_fields = Vec::new();
+    let mut anon = 0;
+    let mut padding = 0;
+
+    for ref field in fields {
+        let fty = &try!(field.get_type().ok_or(ParseError::MissingField("field without type")));
+        let field_name = match field.get_name() {
+            Some(field_name) => field_name,
+            None if is_anonymous_record(fty) => {
+                anon += 1;
+                format!("Anon{}", anon - 1)
+            }
+            // unnamed bitfields only pad, `UINT32 : 3;`
+            None if field.is_bit_field() => {
+                padding += 1;
+                if padding == 1 {
+                    String::from("_bits")
+                } else {
+                    format!("_bits{}", padding)
+                }
+            }
+            None => return Err(ParseError::MissingField("field without name")),
+        };
+
+        let efi_ty = if is_anonymous_record(fty) {
+            let base = format!("{}_{}", name, field_name.to_uppercase());
+            let mut record = try!(to_efi_record(&base, fty, module));
+            record.anonymous = true;
+
+            // the record is seen again when its parent is, but the name may also be taken by
+            // one declared with it or nested elsewhere
+            let mut suffix = 1;
+            loop {
+                let existing = module.records.iter().find(|r| r.name == record.name);
+                match existing {
+                    Some(r) if r.anonymous && same_fields(r, &record) => break,
+                    Some(_) => {
+                        suffix += 1;
+                        record.name = format!("{}_{}", base, suffix);
+                    }
+                    None => {
+                        module.records.push(record.clone());
+                        break;
+                    }
+                }
+            }
+            EfiType::Id(record.name)
+        } else {
+            try!(to_efi_type(fty))
+        };
+
+        efi_fields.push(EfiField {
+            is_reserved: field.get_name().is_none() && field.is_bit_field(),
+            name: field_name,
+            ty: efi_ty,
+            bit_width: if field.is_bit_field() {
+                field.get_bit_field_width().map(|w| w as u32)
+            } else {
+                None
+            },
+            len_const: if fty.get_kind() == TypeKind::ConstantArray {
+                array_len_const(field)
+            } else {
+                None
+            },
+            doc: doc_comment(field),
+        });
+    }
+
+    let kind = try!(cty.get_declaration()
+        .map(|d| d.get_kind())
+        .ok_or(ParseError::MissingField("record type without declaration")));
+
+    Ok(EfiRecord {
+        name: name.to_string(),
+        fields: efi_fields,
+        kind: match kind {
+            EntityKind::StructDecl => EfiRecordKind::Struct,
+            EntityKind::UnionDecl => EfiRecordKind::Union,
+            _ => return Err(ParseError::UnsupportedType { name: ty.get_display_name() }),
+        },
+        doc: cty.get_declaration().and_then(|d| doc_comment(&d)),
+        ms_layout: cty.get_declaration().map_or(false, |d| is_ms_struct(&d)),
+        anonymous: false,
+    })
+}
+
+/// Whether two definitions of a record agree on the names and types of the fields.
+fn same_fields(a: &EfiRecord, b: &EfiRecord) -> bool {
+    a.fields.len() == b.fields.len() &&
+    a.fields.iter().zip(&b.fields).all(|(f, g)| f.name == g.name && f.ty == g.ty)
+}
+
+fn process_typedef(entity: &Entity,
+                   options: &ParseOptions,
+                   module: &mut EfiModule)
+                   -> Result<(), ParseError> {
+    let name = try!(entity.get_name().ok_or(ParseError::MissingField("typedef without name")));
 
     if !name.starts_with("EFI_") {
-        return Err(format!("unknown typedef {}", name));
+        return Err(ParseError::Malformed(format!("unknown typedef {}", name)));
     }
     if name.ends_with("_PROTOCOL") {
+        // the struct is picked up by process_struct, unless the header only forward declares it
+        let defined = entity.get_typedef_underlying_type()
+            .and_then(|t| t.get_canonical_type().get_declaration())
+            .and_then(|d| d.get_definition())
+            .is_some();
+        if !defined && !module.protocols.iter().any(|p| p.name == name) {
+            module.protocols.push(EfiProtocol {
+                name: name,
+                methods: Vec::new(),
+                fields: Vec::new(),
+                deprecated: is_deprecated(entity),
+                guid: None,
+                opaque: true,
+            });
+        }
         return Ok(());
     }
 
-    let ty = try!(entity.get_typedef_underlying_type().ok_or("efi typedef without type"));
+    let ty = try!(entity.get_typedef_underlying_type()
+        .ok_or(ParseError::MissingField("efi typedef without type")));
     let cty = ty.get_canonical_type();
-    if let Some(fields) = cty.get_fields() {
-        let mut efi_fields = Vec::new();
+    if cty.get_fields().is_some() {
+        let mut record = try!(to_efi_record(&name, &ty, module));
+        record.doc = doc_comment(entity).or(record.doc);
 
-        for ref field in fields {
-            let ty = &try!(field.get_type().ok_or("field without type"));
-            efi_fields.push(EfiField {
-                name: try!(field.get_name().ok_or("field without name")),
-                ty: try!(to_efi_type(ty)),
-            });
+        // a forward declaration may be seen before or after the definition, keep the latter
+        match module.records.iter().position(|r| r.name == record.name) {
+            Some(i) if module.records[i].anonymous => {
+                return Err(ParseError::Malformed(format!("{} is already the name of an \
+                                                          anonymous member",
+                                                         record.name)));
+            }
+            Some(i) => {
+                let conflicts = !record.fields.is_empty() &&
+                                !same_fields(&module.records[i], &record);
+                if module.records[i].fields.is_empty() {
+                    module.records[i] = record;
+                } else if conflicts {
+                    return Err(ParseError::Malformed(format!("conflicting definitions of {}",
+                                                             record.name)));
+                }
+            }
+            None => module.records.push(record),
         }
-
-        let kind = try!(ty.get_declaration()
-            .map(|d| d.get_kind())
-            .ok_or("record type without declaration"));
-
-        module.records.push(EfiRecord {
-            name: name,
-            fields: efi_fields,
-            kind: match kind {
-                EntityKind::StructDecl => EfiRecordKind::Struct,
-                EntityKind::UnionDecl => EfiRecordKind::Union,
-                _ => return Err(String::from(format!("unsupported type {:?}", ty))),
-            },
-        });
     } else if cty.get_kind() == TypeKind::Enum {
+        let decl = try!(ty.get_declaration()
+            .ok_or(ParseError::MissingField("enum without declaration")));
+        let signed = decl.get_enum_underlying_type().map_or(false, |t| {
+            match t.get_canonical_type().get_kind() {
+                TypeKind::CharS | TypeKind::SChar | TypeKind::Short | TypeKind::Int |
+                TypeKind::Long | TypeKind::LongLong | TypeKind::Int128 => true,
+                _ => false,
+            }
+        });
         let mut enm = EfiEnum {
             name: name,
             fields: Vec::new(),
+            signed: signed,
         };
-        let decl = try!(ty.get_declaration().ok_or("enum without declaration"));
         try!(process_enum_decls(&decl, &mut enm));
         module.enums.push(enm);
+    } else if let Some(ref ptype) = cty.get_pointee_type()
+        .and_check(|ty| ty.get_kind() == TypeKind::FunctionPrototype) {
+        let mut method = try!(to_efi_method(ptype));
+        try!(process_method_args(entity, options, &mut method.args.iter_mut()));
+        link_lengths(&mut method);
+        method.name = name;
+        method.deprecated = is_deprecated(entity);
+        method.doc = doc_comment(entity);
+        method.const_ptr = cty.is_const_qualified();
+        if is_efiapi(entity) {
+            method.abi = EfiAbi::Efiapi;
+        }
+        module.callbacks.push(method);
+    } else {
+        match to_efi_type(&ty) {
+            Ok(EfiType::Id(_)) |
+            Err(_) => {
+                module.warnings.push(format!("{}: typedef of {} is not a record, enum, function \
+                                              pointer or builtin type",
+                                             name,
+                                             ty.get_display_name()))
+            }
+            Ok(alias) => {
+                if !module.aliases.iter().any(|a| a.0 == name) {
+                    module.aliases.push((name, alias));
+                }
+            }
+        }
     }
 
     Ok(())
 }
 
-fn process_method_args(entity: &Entity, args: &mut Iterator<Item = &mut EfiArg>) {
+/// Links size arguments to the buffer next to them, e.g. `BufferSize` to `Buffer` or `DataSize`
+/// to `Data`, and records the pairs in `EfiMethod::slices`. Relies on the names
+/// `process_method_args` assigns.
+fn link_lengths(method: &mut EfiMethod) {
+    let is_size = |ty: &EfiType| match *ty {
+        EfiType::UIntN | EfiType::UInt32 | EfiType::UInt64 => true,
+        _ => false,
+    };
+    let is_buffer = |ty: &EfiType| match *ty {
+        EfiType::Ptr(_) | EfiType::ConstPtr(_) | EfiType::Ucs2Ptr => true,
+        _ => false,
+    };
+    let names_length = |len: &str, buffer: &str| {
+        !buffer.is_empty() && len.starts_with(buffer) &&
+        ["Size", "Length"].contains(&&len[buffer.len()..])
+    };
+
+    let args = &mut method.args;
+    for i in 0..args.len() {
+        // sizes the callee updates are passed by pointer
+        let sized = match args[i].ty {
+            EfiType::Ptr(ref ty) => is_size(ty),
+            ref ty => is_size(ty),
+        };
+        if !sized {
+            continue;
+        }
+
+        let buffer = [i.wrapping_sub(1), i + 1]
+            .iter()
+            .cloned()
+            .find(|&j| {
+                j < args.len() && is_buffer(&args[j].ty) &&
+                names_length(&args[i].name, &args[j].name)
+            });
+        args[i].length_of = buffer;
+    }
+
+    method.slices = args.iter()
+        .enumerate()
+        .filter_map(|(i, arg)| {
+            arg.length_of.map(|j| {
+                SliceParam {
+                    ptr_index: j,
+                    len_index: i,
+                    dir: args[j].dir,
+                }
+            })
+        })
+        .collect();
+}
+
+/// The element type as written of an array parameter the prototype only has the decayed type of.
+///
+/// libclang reports decayed types as unexposed, so `to_efi_argtype` can only map the canonical
+/// pointer, losing markers such as `EFI_STRING_ID` the element type is spelled with.
+fn decayed_element_type(param: &Entity) -> Option<EfiType> {
+    let pointee = match param.get_type() {
+        Some(ty) if ty.get_kind() == TypeKind::Unexposed => {
+            match ty.get_canonical_type().get_pointee_type() {
+                Some(pointee) => pointee,
+                None => return None,
+            }
+        }
+        _ => return None,
+    };
+
+    param.get_children()
+        .iter()
+        .find(|c| c.get_kind() == EntityKind::TypeRef)
+        .and_then(|r| r.get_reference())
+        .and_then(|d| d.get_type())
+        // the reference may also name what a pointer element points to
+        .and_check(|elem| {
+            let decl = elem.get_canonical_type().get_declaration();
+            decl.is_some() && decl == pointee.get_declaration()
+        })
+        .and_then(|elem| to_efi_type(&elem).ok())
+}
+
+/// Names the arguments of a method after the parameters of its declaration, by position.
+fn process_method_args(entity: &Entity,
+                       options: &ParseOptions,
+                       args: &mut Iterator<Item = &mut EfiArg>)
+                       -> Result<(), ParseError> {
     if entity.get_kind() == EntityKind::ParmDecl {
-        if let Some(name) = entity.get_name() {
-            let arg = args.next().expect("missing argument name");
-            arg.name = name;
+        // direction and optional markers are unnamed parameters of their own
+        let is_marker = entity.get_type()
+            .map_or(false, |t| to_efi_argdir(&t).is_some() || to_efi_argopt(&t));
+        if !is_marker {
+            let arg = try!(args.next()
+                .ok_or(ParseError::Malformed("more parameters than arguments".to_string())));
+            if let Some(name) = entity.get_name() {
+                arg.name = match options.arg_name_fn {
+                    Some(ref f) => f(&name),
+                    None => name,
+                };
+            }
+            if let Some(elem) = decayed_element_type(entity) {
+                match arg.ty {
+                    EfiType::Ptr(ref mut ty) |
+                    EfiType::ConstPtr(ref mut ty) => **ty = elem,
+                    _ => {}
+                }
+            }
         }
+        // the parameters of a function pointer parameter are not ours
+        return Ok(());
     }
 
     for ref child in entity.get_children() {
-        process_method_args(child, args);
+        try!(process_method_args(child, options, args));
     }
+    Ok(())
 }
 
-fn process_struct(entity: &Entity, module: &mut EfiModule) -> Result<(), String> {
+fn process_struct(entity: &Entity,
+                  options: &ParseOptions,
+                  module: &mut EfiModule)
+                  -> Result<(), ParseError> {
     if let Some(name) = entity.get_name()
-        .and_check(|n| n.starts_with("_EFI") && n.ends_with("PROTOCOL")) {
-        let name = &name[1..];
+        .and_check(|n| n.trim_left_matches('_').starts_with("EFI") && n.ends_with("PROTOCOL")) {
+        // the tag usually carries a leading underscore, but not always
+        let name = if name.starts_with('_') { &name[1..] } else { &name[..] };
 
-        if module.protocols.iter().any(|m| m.name == name) {
-            return Ok(());
+        let definition = entity.get_definition();
+        match module.protocols.iter().position(|m| m.name == name) {
+            // a definition replaces what a typedef of the forward declaration provided
+            Some(i) if module.protocols[i].opaque && definition.is_some() => {
+                module.protocols.remove(i);
+            }
+            Some(_) => return Ok(()),
+            None => {}
         }
 
         let mut protocol = EfiProtocol {
             name: String::from(name),
             methods: Vec::new(),
             fields: Vec::new(),
+            deprecated: is_deprecated(entity),
+            guid: None,
+            opaque: definition.is_none(),
         };
 
         let fields = try!(entity.get_type()
             .and_then(|t| t.get_fields())
-            .ok_or("protocol definition without fields"));
+            .ok_or(ParseError::MissingField("protocol definition without fields")));
 
         for ref field in fields {
-            let name = try!(field.get_name().ok_or("field lacks name"));
-            let ftype = &try!(field.get_type().ok_or("protocol field lacks type"));
+            let name = try!(field.get_name().ok_or(ParseError::MissingField("field lacks name")));
+            let ftype = &try!(field.get_type()
+                .ok_or(ParseError::MissingField("protocol field lacks type")));
 
             if let Some(ref ptype) = ftype.get_canonical_type()
                 .get_pointee_type()
                 .and_check(|ty| ty.get_kind() == TypeKind::FunctionPrototype) {
                 let decl = &try!(field.get_type()
                     .and_then(|t| t.get_declaration())
-                    .ok_or("method lacks declaration"));
+                    .ok_or(ParseError::MissingField("method lacks declaration")));
                 let mut method = try!(to_efi_method(ptype));
-                process_method_args(decl, &mut method.args.iter_mut());
+                try!(process_method_args(decl, options, &mut method.args.iter_mut()));
+                link_lengths(&mut method);
                 method.name = name;
+                method.deprecated = is_deprecated(field) || is_deprecated(decl);
+                method.doc = doc_comment(field).or_else(|| doc_comment(decl));
+                method.typedef = decl.get_name();
+                method.const_ptr = ftype.get_canonical_type().is_const_qualified();
+                if is_efiapi(field) || is_efiapi(decl) {
+                    method.abi = EfiAbi::Efiapi;
+                }
                 protocol.methods.push(method);
             } else {
                 protocol.fields.push(EfiField {
                     name: name,
                     ty: try!(to_efi_type(ftype)),
+                    is_reserved: false,
+                    bit_width: None,
+                    len_const: None,
+                    doc: doc_comment(field),
                 });
             }
         }
@@ -245,50 +813,947 @@ fn process_struct(entity: &Entity, module: &mut EfiModule) -> Result<(), String>
     Ok(())
 }
 
-fn process_tu(entity: &Entity, efi_header: &Path, module: &mut EfiModule) -> Result<(), String> {
-    if entity.get_location()
-        .and_check(|loc| loc.get_file_location().file.get_path() == efi_header)
-        .is_some() {
-        match entity.get_kind() {
-            EntityKind::TypedefDecl => return process_typedef(entity, module),
-            EntityKind::StructDecl => return process_struct(entity, module),
-            _ => {}
+fn process_function(entity: &Entity,
+                    options: &ParseOptions,
+                    module: &mut EfiModule)
+                    -> Result<(), ParseError> {
+    if !entity.is_definition() {
+        return Ok(());
+    }
+
+    let name = try!(entity.get_name().ok_or(ParseError::MissingField("function lacks name")));
+    let ty = &try!(entity.get_type().ok_or(ParseError::MissingField("function lacks type")));
+    let mut method = try!(to_efi_method(ty));
+    try!(process_method_args(entity, options, &mut method.args.iter_mut()));
+    link_lengths(&mut method);
+    method.name = name;
+    method.deprecated = is_deprecated(entity);
+    method.doc = doc_comment(entity);
+    if is_efiapi(entity) {
+        method.abi = EfiAbi::Efiapi;
+    }
+
+    module.functions.push(method);
+    Ok(())
+}
+
+/// Returns the replacement tokens of an object-like macro, `None` for function-like ones.
+fn macro_body(entity: &Entity) -> Option<Vec<String>> {
+    let range = match entity.get_range() {
+        Some(range) => range,
+        None => return None,
+    };
+    let tokens = range.tokenize();
+    let offset = |t: &Token, end: bool| {
+        let r = t.get_range();
+        (if end { r.get_end() } else { r.get_start() }).get_file_location().offset
+    };
+
+    // `FOO(x)` is function-like, `FOO (x)` is not
+    if tokens.len() > 1 && tokens[1].get_spelling() == "(" &&
+       offset(&tokens[0], true) == offset(&tokens[1], false) {
+        return None;
+    }
+
+    // some libclang versions hand out one token past the end of the definition
+    let end = range.get_end().get_file_location().offset;
+    Some(tokens.iter()
+        .skip(1)
+        .filter(|t| offset(t, false) <= end)
+        .map(|t| t.get_spelling())
+        .collect())
+}
+
+fn process_macro(entity: &Entity,
+                 options: &ParseOptions,
+                 module: &mut EfiModule)
+                 -> Result<(), ParseError> {
+    let name = try!(entity.get_name().ok_or(ParseError::MissingField("macro without name")));
+
+    // a macro hides the template's marker typedef, leaving a builtin type we can't map
+    if MARKER_TYPEDEFS.contains(&&*name) {
+        let message = format!("{} is defined as a macro, the template already provides it as a \
+                               typedef",
+                              name);
+        if !options.lenient {
+            return Err(ParseError::Malformed(message));
         }
+        module.warnings.push(format!("{}: {}", name, message));
+    }
+    let body = match macro_body(entity) {
+        Some(body) => body,
+        None => return Ok(()),
+    };
+
+    if name.ends_with("_REVISION") {
+        let value = {
+            let revisions = &module.revisions;
+            let lookup = |n: &str| revisions.iter().find(|r| r.0 == n).map(|r| r.1);
+            eval_int(&body, &lookup)
+        };
+        if let Some(value) = value {
+            module.revisions.push((name.clone(), value));
+        }
+    }
+
+    let value = match eval_guid(&body) {
+        Some(guid) => Some(EfiConstValue::Guid(guid)),
+        None => {
+            let constants = &module.constants;
+            let lookup = |n: &str| {
+                constants.iter().find(|c| c.name == n).and_then(|c| match c.value {
+                    EfiConstValue::Int(val) => Some(val),
+                    EfiConstValue::Guid(_) => None,
+                })
+            };
+            eval_int(&body, &lookup).map(EfiConstValue::Int)
+        }
+    };
+    if let Some(value) = value {
+        module.constants.push(EfiConstant {
+            name: name,
+            value: value,
+        });
+    }
+
+    Ok(())
+}
+
+fn is_static_assert(entity: &Entity) -> bool {
+    entity.get_range()
+        .and_then(|r| r.tokenize().first().map(|t| t.get_spelling()))
+        .map_or(false, |t| t == "_Static_assert")
+}
+
+fn process_static_assert(entity: &Entity, module: &mut EfiModule) -> Result<(), ParseError> {
+    let spelling = |e: &Entity| {
+        e.get_range().map(|range| {
+            // see `macro_body`, the tokens may run past the end of the range
+            let end = range.get_end().get_file_location().offset;
+            range.tokenize()
+                .iter()
+                .filter(|t| t.get_range().get_start().get_file_location().offset < end)
+                .map(|t| t.get_spelling())
+                .collect::<Vec<_>>()
+        })
+    };
+
+    let children = entity.get_children();
+    let expr = try!(children.get(0)
+        .and_then(|e| spelling(e))
+        .ok_or(ParseError::MissingField("static assertion without expression")));
+    let msg = children.get(1)
+        .and_then(|e| spelling(e))
+        .map(|toks| toks.iter().map(|t| t.trim_matches('"')).collect::<Vec<_>>().concat())
+        .unwrap_or(String::new());
+
+    module.static_asserts.push((expr.join(" "), msg));
+    Ok(())
+}
+
+fn mark_reserved(module: &mut EfiModule, patterns: &[String]) {
+    let patterns: Vec<_> = patterns.iter().map(|p| p.to_lowercase()).collect();
+    let fields = module.records
+        .iter_mut()
+        .flat_map(|r| r.fields.iter_mut())
+        .chain(module.protocols.iter_mut().flat_map(|p| p.fields.iter_mut()));
+
+    for field in fields {
+        let name = field.name.trim_left_matches('_').to_lowercase();
+        field.is_reserved |= patterns.iter().any(|p| name.starts_with(p.as_str()));
+    }
+}
+
+fn attach_guids(module: &mut EfiModule) {
+    for proto in &mut module.protocols {
+        let name = format!("{}_GUID", proto.name);
+        proto.guid = module.constants.iter().find(|c| c.name == name).and_then(|c| match c.value {
+            EfiConstValue::Guid(guid) => Some(guid),
+            EfiConstValue::Int(_) => None,
+        });
     }
+}
 
+/// State shared while walking a translation unit.
+struct Context<'a> {
+    efi_headers: &'a [PathBuf],
+    aux_header: &'a Path,
+    filter: IncludeFilter,
+    options: &'a ParseOptions,
+    entities: usize,
+}
+
+fn process_tu(entity: &Entity,
+              ctx: &mut Context,
+              module: &mut EfiModule)
+              -> Result<(), ParseError> {
+    ctx.entities += 1;
+    if let Some(max) = ctx.options.max_entities.and_check(|&max| ctx.entities > max) {
+        return Err(ParseError::Malformed(format!("more than {} entities", max)));
+    }
+
+    if ctx.filter.accepts(entity, ctx.efi_headers, ctx.aux_header) {
+        let result = match entity.get_kind() {
+            EntityKind::TypedefDecl => process_typedef(entity, ctx.options, module),
+            EntityKind::StructDecl => process_struct(entity, ctx.options, module),
+            EntityKind::MacroDefinition => process_macro(entity, ctx.options, module),
+            // libclang only has a cursor kind of its own for these from 3.9 on
+            EntityKind::UnexposedDecl if is_static_assert(entity) => {
+                process_static_assert(entity, module)
+            }
+            EntityKind::FunctionDecl if ctx.options.capture_inline_fns => {
+                process_function(entity, ctx.options, module)
+            }
+            _ => return walk_children(entity, ctx, module),
+        };
+
+        return match result {
+            Err(ParseError::UnsupportedType { .. }) |
+            Err(ParseError::Malformed(_)) if ctx.options.lenient => {
+                let name = entity.get_name().unwrap_or_else(|| String::from("<anonymous>"));
+                module.warnings.push(format!("{}: {}", name, result.unwrap_err()));
+                Ok(())
+            }
+            result => result,
+        };
+    }
+
+    walk_children(entity, ctx, module)
+}
+
+fn walk_children(entity: &Entity,
+                 ctx: &mut Context,
+                 module: &mut EfiModule)
+                 -> Result<(), ParseError> {
     for ref child in entity.get_children() {
-        try!(process_tu(child, efi_header, module));
+        try!(process_tu(child, ctx, module));
     }
 
     Ok(())
 }
 
-fn write_aux_header<P: AsRef<Path>, Q: AsRef<Path>>(efi_header: P,
-                                                    out_header: Q)
-                                                    -> io::Result<PathBuf> {
-    let template = include_str!("template.h");
-    let efi_header_path = try!(canonicalize(efi_header));
+/// The generated header parsed in place of the EFI header, removed again once dropped.
+struct AuxHeader {
+    path: PathBuf,
+}
+
+impl Drop for AuxHeader {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// The raw bytes of `path`, which the include needs no matter the encoding.
+#[cfg(unix)]
+fn path_bytes(path: &Path) -> Option<&[u8]> {
+    use std::os::unix::ffi::OsStrExt;
+    Some(path.as_os_str().as_bytes())
+}
+
+#[cfg(not(unix))]
+fn path_bytes(path: &Path) -> Option<&[u8]> {
+    path.to_str().map(|p| p.as_bytes())
+}
+
+/// The template followed by an include of each of `efi_headers`, whose paths are escaped like
+/// string literals.
+fn aux_header_source(efi_headers: &[PathBuf]) -> io::Result<Vec<u8>> {
+    let mut source = Vec::from(include_str!("template.h").as_bytes());
+
+    for efi_header in efi_headers {
+        let path = try!(path_bytes(efi_header).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput,
+                           format!("unsupported header path {:?}", efi_header))
+        }));
+        source.extend_from_slice(b"\n#include \"");
+        for &byte in path {
+            match byte {
+                b'\\' | b'"' => source.extend_from_slice(&[b'\\', byte]),
+                b'\n' => source.extend_from_slice(b"\\n"),
+                _ => source.push(byte),
+            }
+        }
+        source.push(b'"');
+    }
+
+    Ok(source)
+}
+
+/// Writes `aux_header_source` to a fresh file in the temporary directory, so that concurrent
+/// parses don't clobber each other.
+fn write_aux_header<P: AsRef<Path>>(efi_headers: &[P]) -> io::Result<(AuxHeader, Vec<PathBuf>)> {
+    static COUNTER: AtomicUsize = ATOMIC_USIZE_INIT;
+
+    let efi_header_paths: Vec<_> = try!(efi_headers.iter().map(canonicalize).collect());
+    let header = try!(aux_header_source(&efi_header_paths));
+
+    loop {
+        let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.subsec_nanos());
+        let count = COUNTER.fetch_add(1, Ordering::SeqCst);
+        let path = env::temp_dir().join(format!("efi-bindgen-{}-{}.h", nanos, count));
+
+        match OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(mut file) => {
+                let aux = AuxHeader { path: path };
+                try!(file.write_all(&header));
+                return Ok((aux, efi_header_paths));
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+pub fn parse(efi_header: &str) -> Result<EfiModule, ParseError> {
+    parse_with_options(efi_header, &ParseOptions::default())
+}
+
+/// Like `parse`, passing `clang_args` on to clang.
+pub fn parse_with_args(efi_header: &str, clang_args: &[&str]) -> Result<EfiModule, ParseError> {
+    let options = ParseOptions {
+        clang_args: clang_args.iter().map(|a| String::from(*a)).collect(),
+        ..ParseOptions::default()
+    };
+    parse_with_options(efi_header, &options)
+}
+
+/// Where a serialized module of `efi_header` is looked for, e.g. `BlockIo.h.efi-ir.json` next to
+/// `BlockIo.h`. See `ParseOptions::prefer_sidecar`.
+pub fn sidecar_path(efi_header: &str) -> PathBuf {
+    PathBuf::from(format!("{}.efi-ir.json", efi_header))
+}
+
+/// The module serialized at `path`, `None` if there is no such file.
+#[cfg(feature = "serialize")]
+fn load_sidecar(path: &Path) -> Result<Option<EfiModule>, ParseError> {
+    let file = match fs::File::open(path) {
+        Ok(file) => file,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(ParseError::Io(e)),
+    };
+    serde_json::from_reader(file)
+        .map(Some)
+        .map_err(|e| ParseError::Malformed(format!("{}: {}", path.display(), e)))
+}
+
+#[cfg(not(feature = "serialize"))]
+fn load_sidecar(_path: &Path) -> Result<Option<EfiModule>, ParseError> {
+    Ok(None)
+}
+
+pub fn parse_with_options(efi_header: &str,
+                          options: &ParseOptions)
+                          -> Result<EfiModule, ParseError> {
+    if options.prefer_sidecar {
+        if let Some(module) = try!(load_sidecar(&sidecar_path(efi_header))) {
+            return Ok(module);
+        }
+    }
+
+    let (aux, efi_headers) = try!(write_aux_header(&[efi_header]));
+    parse_tu(&aux.path, &efi_headers, &[], options)
+}
+
+/// Parses `headers` as one translation unit, so types shared between them appear only once.
+///
+/// A record defined differently by two of the headers is an error.
+pub fn parse_many(headers: &[&str], clang_args: &[&str]) -> Result<EfiModule, ParseError> {
+    let options = ParseOptions {
+        clang_args: clang_args.iter().map(|a| String::from(*a)).collect(),
+        ..ParseOptions::default()
+    };
+    parse_many_with_options(headers, &options)
+}
+
+/// Like `parse_many`, with full control over parsing.
+pub fn parse_many_with_options(headers: &[&str],
+                               options: &ParseOptions)
+                               -> Result<EfiModule, ParseError> {
+    let (aux, efi_headers) = try!(write_aux_header(headers));
+    parse_tu(&aux.path, &efi_headers, &[], options)
+}
 
-    let header = format!("{}\n#include \"{}\"",
-                         template,
-                         (*efi_header_path).to_string_lossy());
+/// Like `parse_with_args`, but takes the contents of the header rather than its path.
+///
+/// Nothing is written to disk, clang gets both the header and the generated header including it
+/// as unsaved files.
+pub fn parse_source(source: &str, clang_args: &[&str]) -> Result<EfiModule, ParseError> {
+    if source.contains('\0') {
+        return Err(ParseError::Malformed("header contains a NUL byte".to_string()));
+    }
 
-    let mut aux = try!(File::create(out_header));
-    try!(aux.write_all(header.as_bytes()));
-    Ok(efi_header_path)
+    let options = ParseOptions {
+        clang_args: clang_args.iter().map(|a| String::from(*a)).collect(),
+        ..ParseOptions::default()
+    };
+    let dir = env::temp_dir();
+    let efi_headers = [dir.join("efi-bindgen-source.h")];
+    let aux_header = dir.join("efi-bindgen-source-aux.h");
+    let aux_source = try!(String::from_utf8(try!(aux_header_source(&efi_headers)))
+        .map_err(|_| ParseError::Malformed(format!("unsupported temporary directory {:?}", dir))));
+    let unsaved = [Unsaved::new(&aux_header, aux_source), Unsaved::new(&efi_headers[0], source)];
+    parse_tu(&aux_header, &efi_headers, &unsaved, &options)
 }
 
-pub fn parse(efi_header: &str) -> Result<EfiModule, Box<Error>> {
-    let efi_header = try!(write_aux_header(efi_header, "aux.h"));
-    let clang = try!(Clang::new());
+fn parse_tu(aux_header: &Path,
+            efi_headers: &[PathBuf],
+            unsaved: &[Unsaved],
+            options: &ParseOptions)
+            -> Result<EfiModule, ParseError> {
+    let filter = match options.include_filter {
+        IncludeFilter::ProjectHeaders(ref dirs) => {
+            let dirs = try!(dirs.iter().map(canonicalize).collect());
+            IncludeFilter::ProjectHeaders(dirs)
+        }
+        ref filter => filter.clone(),
+    };
+
+    let clang = try!(Clang::new().map_err(ParseError::Clang));
     let index = Index::new(&clang, false, true);
-    let tu = try!(index.parser("aux.h").arguments(["-fsyntax-only"].as_ref()).parse());
+    let mut args = vec!["-fsyntax-only"];
+    args.extend(options.clang_args.iter().map(|a| a.as_str()));
+    let tu = try!(index.parser(aux_header)
+        .arguments(&args)
+        .unsaved(unsaved)
+        .detailed_preprocessing_record(true)
+        .parse()
+        .map_err(|e| ParseError::Clang(e.to_string())));
     let mut proto = EfiModule {
         protocols: Vec::new(),
         records: Vec::new(),
         enums: Vec::new(),
+        functions: Vec::new(),
+        callbacks: Vec::new(),
+        aliases: Vec::new(),
+        revisions: Vec::new(),
+        constants: Vec::new(),
+        static_asserts: Vec::new(),
+        extern_types: options.extern_types.clone(),
+        warnings: Vec::new(),
     };
 
-    try!(process_tu(&tu.get_entity(), &efi_header, &mut proto));
+    let mut ctx = Context {
+        efi_headers: efi_headers,
+        aux_header: aux_header,
+        filter: filter,
+        options: options,
+        entities: 0,
+    };
+    try!(process_tu(&tu.get_entity(), &mut ctx, &mut proto));
+    mark_reserved(&mut proto, &options.reserved_patterns);
+    attach_guids(&mut proto);
     Ok(proto)
 }
+
+/// Parses every header matching the glob `pattern`, e.g. `Include/Protocol/*.h`, into one module.
+///
+/// In lenient mode headers that fail to parse are skipped and recorded in `EfiModule::warnings`.
+pub fn parse_glob(pattern: &str, options: &ParseOptions) -> Result<EfiModule, ParseError> {
+    let paths = try!(glob(pattern)
+        .map_err(|e| ParseError::Malformed(format!("bad pattern {}: {}", pattern, e))));
+    let mut module = EfiModule {
+        protocols: Vec::new(),
+        records: Vec::new(),
+        enums: Vec::new(),
+        functions: Vec::new(),
+        callbacks: Vec::new(),
+        aliases: Vec::new(),
+        revisions: Vec::new(),
+        constants: Vec::new(),
+        static_asserts: Vec::new(),
+        extern_types: options.extern_types.clone(),
+        warnings: Vec::new(),
+    };
+
+    for entry in paths {
+        let path = try!(entry.map_err(|e| io::Error::new(e.error().kind(), e.to_string())));
+        let path = try!(path.to_str()
+            .map(String::from)
+            .ok_or(ParseError::Malformed(format!("{} is not UTF-8", path.display()))));
+
+        match parse_with_options(&path, options) {
+            Ok(header) => module.merge(header),
+            Err(e) => {
+                if !options.lenient {
+                    return Err(e);
+                }
+                module.warnings.push(format!("skipping {}: {}", path, e));
+            }
+        }
+    }
+
+    Ok(module)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record<'a>(module: &'a EfiModule, name: &str) -> &'a EfiRecord {
+        module.records.iter().find(|r| r.name == name).expect(name)
+    }
+
+    #[test]
+    fn anonymous_union_member() {
+        let module = parse_source("typedef union { UINT32 Addr[4]; } EFI_IP_ADDRESS;
+                                   typedef struct {
+                                       EFI_IP_ADDRESS Address;
+                                       union { UINT8 Byte; UINT16 Word; } Value;
+                                   } EFI_FOO;",
+                                  &[])
+            .unwrap();
+
+        let foo = record(&module, "EFI_FOO");
+        assert_eq!(foo.fields[0].ty, EfiType::Id(String::from("EFI_IP_ADDRESS")));
+        assert_eq!(foo.fields[1].ty, EfiType::Id(String::from("EFI_FOO_VALUE")));
+        assert_eq!(record(&module, "EFI_FOO_VALUE").kind, EfiRecordKind::Union);
+        assert!(record(&module, "EFI_FOO_VALUE").anonymous);
+        assert!(!record(&module, "EFI_IP_ADDRESS").anonymous);
+        assert!(!module.records.iter().any(|r| r.name == "EFI_FOO_ADDRESS"));
+    }
+
+    #[test]
+    fn anonymous_member_named_like_a_record() {
+        let module = parse_source("typedef struct { UINT32 Raw; } EFI_FOO_VALUE;
+                                   typedef struct {
+                                       union { UINT8 Byte; UINT16 Word; } Value;
+                                   } EFI_FOO;",
+                                  &[])
+            .unwrap();
+
+        let foo = record(&module, "EFI_FOO");
+        assert_eq!(foo.fields[0].ty, EfiType::Id(String::from("EFI_FOO_VALUE_2")));
+        assert_eq!(record(&module, "EFI_FOO_VALUE").fields[0].name, "Raw");
+        assert_eq!(record(&module, "EFI_FOO_VALUE_2").kind, EfiRecordKind::Union);
+
+        let result = parse_source("typedef struct {
+                                       union { UINT8 Byte; UINT16 Word; } Value;
+                                   } EFI_FOO;
+                                   typedef struct { UINT32 Raw; } EFI_FOO_VALUE;",
+                                  &[]);
+        match result {
+            Err(ParseError::Malformed(message)) => assert!(message.contains("EFI_FOO_VALUE")),
+            result => panic!("expected a malformed header, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn union_members_keep_declaration_order() {
+        let module = parse_source("typedef union {
+                                       UINT64 Raw;
+                                       UINT8 Bytes[8];
+                                       struct { UINT32 Low; UINT32 High; } Parts;
+                                       UINT16 Words[4];
+                                   } EFI_REGISTER;",
+                                  &[])
+            .unwrap();
+
+        let names: Vec<_> = record(&module, "EFI_REGISTER")
+            .fields
+            .iter()
+            .map(|f| f.name.as_str())
+            .collect();
+        assert_eq!(names, vec!["Raw", "Bytes", "Parts", "Words"]);
+    }
+
+    #[test]
+    fn array_parameter_keeps_marker_element_type() {
+        let module = parse_source("typedef UINT16 EFI_STRING_ID;
+                                   typedef EFI_STATUS (EFIAPI *EFI_GET_STRINGS)(
+                                       IN EFI_STRING_ID Ids[4],
+                                       IN const EFI_STRING_ID Defaults[4],
+                                       OUT UINTN Sizes[4]);",
+                                  &[])
+            .unwrap();
+
+        let args: Vec<_> = module.callbacks[0].args.iter().map(|a| a.ty.clone()).collect();
+        assert_eq!(args,
+                   vec![EfiType::Ptr(Box::new(EfiType::StringId)),
+                        EfiType::ConstPtr(Box::new(EfiType::StringId)),
+                        EfiType::Ptr(Box::new(EfiType::UIntN))]);
+    }
+
+    #[test]
+    fn bitfield_widths() {
+        let module = parse_source("typedef struct {
+                                       UINT32 Value;
+                                       UINT32 Low : 4;
+                                       UINT32 High : 4;
+                                   } EFI_MODE;",
+                                  &[])
+            .unwrap();
+
+        let widths: Vec<_> = record(&module, "EFI_MODE")
+            .fields
+            .iter()
+            .map(|f| (f.name.as_str(), f.bit_width))
+            .collect();
+        assert_eq!(widths, vec![("Value", None), ("Low", Some(4)), ("High", Some(4))]);
+    }
+
+    #[test]
+    fn unnamed_padding_bitfields() {
+        let module = parse_source("typedef struct {
+                                       UINT32 Low : 4;
+                                       UINT32 : 3;
+                                       UINT32 High : 1;
+                                       UINT32 : 0;
+                                       UINT8 Next;
+                                   } EFI_FLAGS;",
+                                  &[])
+            .unwrap();
+
+        let fields: Vec<_> = record(&module, "EFI_FLAGS")
+            .fields
+            .iter()
+            .map(|f| (f.name.as_str(), f.bit_width, f.is_reserved))
+            .collect();
+        assert_eq!(fields,
+                   vec![("Low", Some(4), false),
+                        ("_bits", Some(3), true),
+                        ("High", Some(1), false),
+                        ("_bits2", Some(0), true),
+                        ("Next", None, false)]);
+    }
+
+    #[test]
+    fn include_filter_selects_contributing_files() {
+        let root = env::temp_dir().join(format!("efi-bindgen-filter-{}", ::std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        let (project, outside) = (root.join("project"), root.join("outside"));
+        fs::create_dir_all(project.join("Include")).unwrap();
+        fs::create_dir_all(&outside).unwrap();
+        for &(ref path, source) in
+            &[(project.join("Main.h"),
+               "#include \"Include/Project.h\"\n#include <Outside.h>
+                typedef struct { EFI_PROJECT P; EFI_OUTSIDE O; } EFI_MAIN;"),
+              (project.join("Include/Project.h"), "typedef struct { UINT8 A; } EFI_PROJECT;"),
+              (outside.join("Outside.h"), "typedef struct { UINT8 A; } EFI_OUTSIDE;")] {
+            fs::File::create(path).unwrap().write_all(source.as_bytes()).unwrap();
+        }
+        let header = project.join("Main.h");
+        let names = |filter| {
+            let options = ParseOptions {
+                include_filter: filter,
+                clang_args: vec![format!("-I{}", outside.display())],
+                ..ParseOptions::default()
+            };
+            let module = parse_with_options(header.to_str().unwrap(), &options).unwrap();
+            module.records.into_iter().map(|r| r.name).collect::<Vec<_>>()
+        };
+
+        let exact = names(IncludeFilter::ExactFile);
+        let projects = names(IncludeFilter::ProjectHeaders(vec![project.clone()]));
+        let all = names(IncludeFilter::All);
+        fs::remove_dir_all(&root).unwrap();
+
+        assert_eq!(exact, vec!["EFI_MAIN"]);
+        assert_eq!(projects, vec!["EFI_PROJECT", "EFI_MAIN"]);
+        assert_eq!(all, vec!["EFI_PROJECT", "EFI_OUTSIDE", "EFI_MAIN"]);
+    }
+
+    #[test]
+    fn header_dir_with_space_and_non_ascii_name() {
+        let dir = env::temp_dir().join(format!("efi-bindgen en-tête {}", ::std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        fs::File::create(dir.join("Other.h"))
+            .unwrap()
+            .write_all(b"typedef struct { UINT8 B; } EFI_B;")
+            .unwrap();
+        let header = dir.join("Main.h");
+        fs::File::create(&header)
+            .unwrap()
+            .write_all(b"#include \"Other.h\"
+                         typedef struct { EFI_B B; } EFI_A;")
+            .unwrap();
+        let result = parse(header.to_str().unwrap());
+        fs::remove_dir_all(&dir).unwrap();
+
+        let module = result.unwrap();
+        assert_eq!(record(&module, "EFI_A").fields[0].ty, EfiType::Id(String::from("EFI_B")));
+    }
+
+    #[test]
+    fn aux_header_escapes_include_paths() {
+        let source = aux_header_source(&[PathBuf::from("/efi/a \"b\"\\c.h")]).unwrap();
+        assert!(source.ends_with(b"\n#include \"/efi/a \\\"b\\\"\\\\c.h\""));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn glob_merges_headers_and_skips_failures() {
+        use std::os::unix::fs::symlink;
+        use std::process;
+
+        let dir = env::temp_dir().join(format!("efi-bindgen-glob-{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        for &(file, source) in &[("A.h", "typedef struct { UINT32 A; } EFI_A;"),
+                                 ("B.h", "typedef struct { UINT8 B; } EFI_B;")] {
+            fs::File::create(dir.join(file)).unwrap().write_all(source.as_bytes()).unwrap();
+        }
+        // dangling, so the header cannot even be opened
+        symlink(dir.join("Missing.h"), dir.join("C.h")).unwrap();
+        let pattern = format!("{}/*.h", dir.display());
+
+        assert!(parse_glob(&pattern, &ParseOptions::default()).is_err());
+
+        let options = ParseOptions { lenient: true, ..ParseOptions::default() };
+        let module = parse_glob(&pattern, &options).unwrap();
+        assert_eq!(record(&module, "EFI_A").fields[0].ty, EfiType::UInt32);
+        assert_eq!(record(&module, "EFI_B").fields[0].ty, EfiType::UInt8);
+        assert_eq!(module.warnings.len(), 1);
+        assert!(module.warnings[0].contains("C.h"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn marker_macro_is_a_warning_when_lenient() {
+        let path = env::temp_dir().join(format!("efi-bindgen-marker-{}.h", ::std::process::id()));
+        fs::File::create(&path)
+            .unwrap()
+            .write_all(b"#define UINTN unsigned long
+                         typedef struct { UINT32 A; } EFI_A;")
+            .unwrap();
+        let strict = parse_with_options(path.to_str().unwrap(), &ParseOptions::default());
+        let options = ParseOptions { lenient: true, ..ParseOptions::default() };
+        let lenient = parse_with_options(path.to_str().unwrap(), &options);
+        fs::remove_file(&path).unwrap();
+
+        match strict {
+            Err(ParseError::Malformed(message)) => assert!(message.contains("UINTN")),
+            result => panic!("expected a malformed header, got {:?}", result),
+        }
+        let module = lenient.unwrap();
+        assert_eq!(record(&module, "EFI_A").fields[0].ty, EfiType::UInt32);
+        assert_eq!(module.warnings.len(), 1);
+        assert!(module.warnings[0].starts_with("UINTN: "));
+    }
+
+    #[test]
+    fn max_entities_bounds_the_walk() {
+        let path = env::temp_dir().join(format!("efi-bindgen-limit-{}.h", ::std::process::id()));
+        let source: String = (0..100)
+            .map(|i| format!("typedef struct {{ UINT8 A; }} EFI_R{};\n", i))
+            .collect();
+        fs::File::create(&path).unwrap().write_all(source.as_bytes()).unwrap();
+        let parse_at_most = |max| {
+            let options = ParseOptions { max_entities: Some(max), ..ParseOptions::default() };
+            parse_with_options(path.to_str().unwrap(), &options)
+        };
+        let limited = parse_at_most(10);
+        let generous = parse_at_most(100000);
+        fs::remove_file(&path).unwrap();
+
+        match limited {
+            Err(ParseError::Malformed(message)) => assert_eq!(message, "more than 10 entities"),
+            result => panic!("expected a malformed header, got {:?}", result),
+        }
+        assert_eq!(generous.unwrap().records.len(), 100);
+    }
+
+    #[test]
+    fn arg_name_fn_renames_every_argument() {
+        let path = env::temp_dir().join(format!("efi-bindgen-args-{}.h", ::std::process::id()));
+        fs::File::create(&path)
+            .unwrap()
+            .write_all(b"typedef struct _EFI_FOO_PROTOCOL EFI_FOO_PROTOCOL;
+                         typedef EFI_STATUS (EFIAPI *EFI_FOO_RESET)(IN EFI_FOO_PROTOCOL *This,
+                                                                    IN UINT32 Value);
+                         struct _EFI_FOO_PROTOCOL { EFI_FOO_RESET Reset; };")
+            .unwrap();
+        let options = ParseOptions {
+            arg_name_fn: Some(Rc::new(|name: &str| name.to_lowercase())),
+            ..ParseOptions::default()
+        };
+        let module = parse_with_options(path.to_str().unwrap(), &options).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let names: Vec<_> = module.protocols[0].methods[0]
+            .args
+            .iter()
+            .map(|a| a.name.as_str())
+            .collect();
+        assert_eq!(names, vec!["this", "value"]);
+    }
+
+    #[test]
+    fn deprecated_protocols_and_methods() {
+        let module =
+            parse_source("typedef struct _EFI_FOO_PROTOCOL EFI_FOO_PROTOCOL;
+                          typedef EFI_STATUS (EFIAPI *EFI_FOO_RESET)(IN EFI_FOO_PROTOCOL *This)
+                              __attribute__((deprecated));
+                          typedef EFI_STATUS (EFIAPI *EFI_FOO_READ)(IN EFI_FOO_PROTOCOL *This);
+                          struct __attribute__((deprecated)) _EFI_FOO_PROTOCOL {
+                              EFI_FOO_RESET Reset;
+                              EFI_FOO_READ Read;
+                              EFI_FOO_READ OldRead __attribute__((deprecated));
+                          };",
+                         &[])
+                .unwrap();
+
+        let proto = &module.protocols[0];
+        assert!(proto.deprecated);
+        let methods: Vec<_> = proto.methods
+            .iter()
+            .map(|m| (m.name.as_str(), m.deprecated))
+            .collect();
+        assert_eq!(methods, vec![("Reset", true), ("Read", false), ("OldRead", true)]);
+    }
+
+    #[test]
+    fn more_parameters_than_arguments_is_an_error() {
+        let clang = Clang::new().unwrap();
+        let index = Index::new(&clang, false, false);
+        let header = "efi-bindgen-params.h";
+        let tu = index.parser(header)
+            .unsaved(&[Unsaved::new(header, "typedef int (*EFI_FN)(int A, int B);")])
+            .parse()
+            .unwrap();
+        let typedef = tu.get_entity()
+            .get_children()
+            .into_iter()
+            .find(|e| e.get_name().map_or(false, |n| n == "EFI_FN"))
+            .unwrap();
+
+        let mut args = vec![EfiArg {
+                                name: String::new(),
+                                ty: EfiType::Int32,
+                                dir: EfiArgDir::In,
+                                optional: false,
+                                restrict: false,
+                                length_of: None,
+                            }];
+        match process_method_args(&typedef, &ParseOptions::default(), &mut args.iter_mut()) {
+            Err(ParseError::Malformed(_)) => {}
+            result => panic!("expected a malformed error, got {:?}", result),
+        }
+        assert_eq!(args[0].name, "A");
+    }
+
+    #[test]
+    fn array_lengths_keep_their_constant() {
+        let module = parse_source("#define EFI_NAME_LEN 16
+                                   typedef struct {
+                                       CHAR16 Name[EFI_NAME_LEN];
+                                       UINT8 Mac[6];
+                                   } EFI_FOO;",
+                                  &[])
+            .unwrap();
+
+        let foo = record(&module, "EFI_FOO");
+        assert_eq!(foo.fields[0].ty, EfiType::Array(Box::new(EfiType::Char16), 16));
+        assert_eq!(foo.fields[0].len_const, Some(String::from("EFI_NAME_LEN")));
+        assert_eq!(foo.fields[1].len_const, None);
+    }
+
+    #[test]
+    fn restrict_qualified_array_arguments() {
+        let module = parse_source("typedef void (EFIAPI *EFI_FOO_COPY)(OUT CHAR8 Dest[restrict],
+                                                                     IN const CHAR8 *Src);",
+                                  &[])
+            .unwrap();
+
+        let args = &module.callbacks[0].args;
+        assert_eq!(args[0].ty, EfiType::Ptr(Box::new(EfiType::Char8)));
+        assert!(args[0].restrict);
+        assert!(!args[1].restrict);
+    }
+
+    #[test]
+    fn const_function_pointer_typedefs() {
+        let module = parse_source("typedef EFI_STATUS (EFIAPI * const EFI_FOO_RESET)(UINT32 Value);
+                                   typedef EFI_STATUS (EFIAPI *EFI_FOO_STOP)(UINT32 Value);",
+                                  &[])
+            .unwrap();
+
+        let consts: Vec<_> = module.callbacks
+            .iter()
+            .map(|c| (c.name.as_str(), c.const_ptr))
+            .collect();
+        assert_eq!(consts, vec![("EFI_FOO_RESET", true), ("EFI_FOO_STOP", false)]);
+        assert_eq!(module.callbacks[0].args[0].ty, EfiType::UInt32);
+    }
+
+    #[test]
+    fn bitfields_in_a_register_union() {
+        let module = parse_source("typedef union {
+                                       struct {
+                                           UINT32 Enable : 1;
+                                           UINT32 Mode : 3;
+                                       } Bits;
+                                       UINT32 Uint32;
+                                   } EFI_REGISTER;",
+                                  &[])
+            .unwrap();
+
+        let register = record(&module, "EFI_REGISTER");
+        assert_eq!(register.kind, EfiRecordKind::Union);
+        let fields: Vec<_> = register.fields
+            .iter()
+            .map(|f| (f.name.as_str(), f.ty.clone()))
+            .collect();
+        assert_eq!(fields,
+                   vec![("Bits", EfiType::Id(String::from("EFI_REGISTER_BITS"))),
+                        ("Uint32", EfiType::UInt32)]);
+
+        let widths: Vec<_> = record(&module, "EFI_REGISTER_BITS")
+            .fields
+            .iter()
+            .map(|f| (f.name.as_str(), f.bit_width))
+            .collect();
+        assert_eq!(widths, vec![("Enable", Some(1)), ("Mode", Some(3))]);
+    }
+
+    #[test]
+    #[cfg(feature = "serialize")]
+    fn sidecar_replaces_clang() {
+        let dir = env::temp_dir().join(format!("efi-bindgen-sidecar-{}", ::std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        // the header itself does not exist, clang would fail on it
+        let header = dir.join("Foo.h");
+        let header = header.to_str().unwrap();
+
+        let module = EfiModule {
+            records: vec![EfiRecord {
+                              name: String::from("EFI_FOO"),
+                              fields: Vec::new(),
+                              kind: EfiRecordKind::Struct,
+                              doc: None,
+                              ms_layout: false,
+                              anonymous: false,
+                          }],
+            ..EfiModule::default()
+        };
+        let json = serde_json::to_string(&module).unwrap();
+        fs::File::create(sidecar_path(header)).unwrap().write_all(json.as_bytes()).unwrap();
+
+        assert!(parse(header).is_err());
+        let options = ParseOptions { prefer_sidecar: true, ..ParseOptions::default() };
+        let loaded = parse_with_options(header, &options).unwrap();
+        assert_eq!(loaded.records[0].name, "EFI_FOO");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn buffer_pairs_become_slices() {
+        let module = parse_source("typedef EFI_STATUS (EFIAPI *EFI_FOO_WRITE)(IN UINT32 Offset,
+                                                                       IN UINTN BufferSize,
+                                                                       IN void *Buffer);",
+                                  &[])
+            .unwrap();
+
+        let write = &module.callbacks[0];
+        assert_eq!(write.args[1].length_of, Some(2));
+        assert_eq!(write.slices,
+                   vec![SliceParam {
+                            ptr_index: 2,
+                            len_index: 1,
+                            dir: EfiArgDir::In,
+                        }]);
+    }
+}
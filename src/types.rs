@@ -1,5 +1,13 @@
-#[derive(Clone, Debug)]
+use std::cmp::max;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use error::ParseError;
+use visit::{walk_mut, EfiVisitor};
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
 pub enum EfiType {
+    Void,
     Status,
     Bool,
     Int8,
@@ -14,72 +22,1361 @@ pub enum EfiType {
     UIntN,
     Char8,
     Char16,
+    Float32,
+    Float64,
+    /// `EFI_STRING`, a `CHAR16 *` to a NUL terminated UCS-2 string.
+    Ucs2Ptr,
+    /// `EFI_STRING_ID`, the 16-bit handle of an HII string.
+    StringId,
+    /// `EFI_GUID`, 16 bytes aligned to 4.
+    Guid,
     Id(String),
     Ptr(Box<EfiType>),
+    /// Pointer to a const qualified pointee, `const T *`.
+    ConstPtr(Box<EfiType>),
+    /// Fixed size array, `C[N]`. A flexible array member `C[]` has length 0.
+    Array(Box<EfiType>, usize),
 }
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
 pub enum EfiArgDir {
     In,
     Out,
+    /// Declared `IN OUT`, the callee reads and updates the pointee.
+    InOut,
 }
 
 #[derive(Clone, Debug)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
 pub struct EfiArg {
     pub name: String,
+    /// Array parameters are recorded as the pointer they decay to.
     pub ty: EfiType,
     pub dir: EfiArgDir,
     pub optional: bool,
+    /// The pointer is `restrict` qualified, e.g. `CHAR8 Buffer[restrict]`.
+    pub restrict: bool,
+    /// Index of the buffer argument this argument holds the size of, e.g. `BufferSize` for
+    /// `Buffer`. Guessed from the argument names.
+    pub length_of: Option<usize>,
+}
+
+impl EfiArg {
+    /// The type the callee writes through an `OUT` or `IN OUT` pointer, e.g. `EFI_FOO *` for
+    /// an `EFI_FOO **` argument. `None` for `IN` arguments and anything but a mutable pointer.
+    pub fn written_type(&self) -> Option<&EfiType> {
+        match (self.dir, &self.ty) {
+            (EfiArgDir::In, _) => None,
+            (_, &EfiType::Ptr(ref ty)) => Some(ty),
+            _ => None,
+        }
+    }
+
+    /// Whether the callee hands back a pointer rather than filling in a buffer, i.e. the
+    /// argument is written through and points to a pointer itself.
+    pub fn is_out_handle(&self) -> bool {
+        self.written_type().map_or(false, |ty| ty.pointer_depth() > 0)
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+pub enum EfiAbi {
+    /// The UEFI calling convention, i.e. the function is declared `EFIAPI`.
+    Efiapi,
+    /// The platform's C calling convention.
+    C,
 }
 
 #[derive(Clone, Debug)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
 pub struct EfiMethod {
     pub name: String,
     pub ty: EfiType,
     pub args: Vec<EfiArg>,
+    pub abi: EfiAbi,
+    pub deprecated: bool,
+    pub doc: Option<String>,
+    /// The function pointer typedef a protocol method is declared with, e.g. `EFI_BLOCK_READ`.
+    pub typedef: Option<String>,
+    /// The function pointer itself is `const`, e.g. `typedef EFI_STATUS (EFIAPI * CONST EFI_FOO)`.
+    pub const_ptr: bool,
+    /// The buffer arguments with a linked size argument, see `EfiArg::length_of`.
+    pub slices: Vec<SliceParam>,
+}
+
+/// A buffer argument and the argument holding its length, which together make up a slice.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+pub struct SliceParam {
+    pub ptr_index: usize,
+    pub len_index: usize,
+    /// Direction of the buffer, whether the callee reads or fills it.
+    pub dir: EfiArgDir,
 }
 
 #[derive(Clone, Debug)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
 pub struct EfiField {
     pub name: String,
     pub ty: EfiType,
+    /// Padding that is not meant to be accessed, inferred from the field name.
+    pub is_reserved: bool,
+    /// Width in bits for bitfields, which share storage units of `ty` with their neighbours.
+    pub bit_width: Option<u32>,
+    /// The constant an array field's length is spelled with, e.g. `EFI_NAME_LEN` in
+    /// `CHAR16 Name[EFI_NAME_LEN];`.
+    pub len_const: Option<String>,
+    pub doc: Option<String>,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
 pub enum EfiRecordKind {
     Union,
     Struct,
 }
 
 #[derive(Clone, Debug)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
 pub struct EfiRecord {
     pub name: String,
     pub fields: Vec<EfiField>,
     pub kind: EfiRecordKind,
+    pub doc: Option<String>,
+    /// Declared `__attribute__((ms_struct))`, bitfields are laid out following the MS rules.
+    pub ms_layout: bool,
+    /// Synthesized for an anonymous record nested in another, named after the enclosing record
+    /// and the member.
+    pub anonymous: bool,
 }
 
 #[derive(Clone, Debug)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
 pub struct EfiVariant {
     pub name: String,
+    /// Negative values of signed enums are sign extended.
     pub value: Option<u64>,
 }
 
 #[derive(Clone, Debug)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
 pub struct EfiEnum {
     pub name: String,
     pub fields: Vec<EfiVariant>,
+    /// Whether the underlying integer type is signed.
+    pub signed: bool,
 }
 
 #[derive(Clone, Debug)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
 pub struct EfiProtocol {
     pub name: String,
     pub methods: Vec<EfiMethod>,
     pub fields: Vec<EfiField>,
+    pub deprecated: bool,
+    /// Taken from the `<name>_GUID` constant, if the headers define one.
+    pub guid: Option<EfiGuid>,
+    /// Only forward declared, so the methods and fields are unknown.
+    pub opaque: bool,
+}
+
+/// `EFI_GUID` in its canonical layout.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+pub struct EfiGuid {
+    pub data1: u32,
+    pub data2: u16,
+    pub data3: u16,
+    pub data4: [u8; 8],
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+pub enum EfiConstValue {
+    Int(u64),
+    Guid(EfiGuid),
 }
 
+/// A `#define` whose body is an integer expression or a GUID initializer.
 #[derive(Clone, Debug)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+pub struct EfiConstant {
+    pub name: String,
+    pub value: EfiConstValue,
+}
+
+#[derive(Clone, Debug, Default)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
 pub struct EfiModule {
     pub protocols: Vec<EfiProtocol>,
     pub records: Vec<EfiRecord>,
     pub enums: Vec<EfiEnum>,
+    /// Helper functions defined in the headers, only captured on request.
+    pub functions: Vec<EfiMethod>,
+    /// Function pointer typedefs such as notification callbacks, named after the typedef.
+    pub callbacks: Vec<EfiMethod>,
+    /// Typedefs of builtin and pointer types such as `EFI_TPL`, with the type they stand for.
+    /// Uses of them are resolved to that type all the same.
+    pub aliases: Vec<(String, EfiType)>,
+    pub revisions: Vec<(String, u64)>,
+    pub constants: Vec<EfiConstant>,
+    /// `_Static_assert` declarations as expression and message, each spelled as in the header.
+    pub static_asserts: Vec<(String, String)>,
+    /// Types provided outside of the parsed headers, mapped to their Rust paths.
+    pub extern_types: HashMap<String, String>,
+    /// Declarations that were left out, each naming the declaration and the reason.
+    pub warnings: Vec<String>,
+}
+
+fn align_to(offset: usize, align: usize) -> usize {
+    (offset + align - 1) / align * align
+}
+
+impl EfiType {
+    /// Size and alignment in bytes for a target with `pointer_width` bit pointers.
+    ///
+    /// Returns `None` for `Void` and if the type names something `module` does not define.
+    pub fn layout(&self, module: &EfiModule, pointer_width: u8) -> Option<(usize, usize)> {
+        let native = pointer_width as usize / 8;
+        match *self {
+            EfiType::Void => None,
+            EfiType::Bool | EfiType::Int8 | EfiType::UInt8 | EfiType::Char8 => Some((1, 1)),
+            EfiType::Int16 | EfiType::UInt16 | EfiType::Char16 | EfiType::StringId => {
+                Some((2, 2))
+            }
+            EfiType::Int32 | EfiType::UInt32 | EfiType::Float32 => Some((4, 4)),
+            EfiType::Guid => Some((16, 4)),
+            EfiType::Int64 | EfiType::UInt64 | EfiType::Float64 => Some((8, 8)),
+            EfiType::Status | EfiType::IntN | EfiType::UIntN | EfiType::Ptr(_) |
+            EfiType::ConstPtr(_) | EfiType::Ucs2Ptr => {
+                Some((native, native))
+            }
+            EfiType::Array(ref ty, len) => {
+                ty.layout(module, pointer_width).map(|(size, align)| (size * len, align))
+            }
+            EfiType::Id(ref name) => {
+                if let Some(record) = module.records.iter().find(|r| r.name == *name) {
+                    record.layout(module, pointer_width)
+                } else if module.enums.iter().any(|e| e.name == *name) {
+                    // UEFI enums are always INT32 sized
+                    Some((4, 4))
+                } else {
+                    None
+                }
+            }
+        }
+    }
+
+    /// Lowers `IntN` and `UIntN`, also behind pointers and in arrays, to the fixed width types of
+    /// a target with `pointer_width` bit pointers. Other widths leave them as they are.
+    pub fn concrete_width(&self, pointer_width: u8) -> EfiType {
+        match (self, pointer_width) {
+            (&EfiType::UIntN, 64) => EfiType::UInt64,
+            (&EfiType::UIntN, 32) => EfiType::UInt32,
+            (&EfiType::IntN, 64) => EfiType::Int64,
+            (&EfiType::IntN, 32) => EfiType::Int32,
+            (&EfiType::Ptr(ref ty), _) => EfiType::Ptr(Box::new(ty.concrete_width(pointer_width))),
+            (&EfiType::ConstPtr(ref ty), _) => {
+                EfiType::ConstPtr(Box::new(ty.concrete_width(pointer_width)))
+            }
+            (&EfiType::Array(ref ty, len), _) => {
+                EfiType::Array(Box::new(ty.concrete_width(pointer_width)), len)
+            }
+            (ty, _) => ty.clone(),
+        }
+    }
+
+    /// Whether both types are passed identically on a target with `pointer_width` bit pointers,
+    /// e.g. `UIntN` and `UInt64` on 64-bit targets.
+    pub fn abi_eq(&self, other: &EfiType, pointer_width: u8) -> bool {
+        // the string types are only named versions of the plain ones
+        let plain = |ty: &EfiType| match *ty {
+            EfiType::Ucs2Ptr => EfiType::Ptr(Box::new(EfiType::Char16)),
+            EfiType::StringId => EfiType::UInt16,
+            ref ty => ty.clone(),
+        };
+
+        match (self, other) {
+            (&EfiType::Ucs2Ptr, _) |
+            (&EfiType::StringId, _) |
+            (_, &EfiType::Ucs2Ptr) |
+            (_, &EfiType::StringId) => plain(self).abi_eq(&plain(other), pointer_width),
+            // constness does not change how a pointer is passed
+            (&EfiType::Ptr(ref a), &EfiType::Ptr(ref b)) |
+            (&EfiType::Ptr(ref a), &EfiType::ConstPtr(ref b)) |
+            (&EfiType::ConstPtr(ref a), &EfiType::Ptr(ref b)) |
+            (&EfiType::ConstPtr(ref a), &EfiType::ConstPtr(ref b)) => a.abi_eq(b, pointer_width),
+            (&EfiType::Array(ref a, n), &EfiType::Array(ref b, m)) => {
+                n == m && a.abi_eq(b, pointer_width)
+            }
+            // the native integer types behave like the fixed width ones of the same size
+            _ => self.concrete_width(pointer_width) == other.concrete_width(pointer_width),
+        }
+    }
+
+    /// Number of pointers in front of the type, e.g. 2 for `EFI_FOO **`. `EFI_STRING` counts as
+    /// the `CHAR16 *` it is.
+    pub fn pointer_depth(&self) -> usize {
+        match *self {
+            EfiType::Ptr(ref ty) |
+            EfiType::ConstPtr(ref ty) => 1 + ty.pointer_depth(),
+            EfiType::Ucs2Ptr => 1,
+            _ => 0,
+        }
+    }
+
+    /// The type itself followed by everything behind its pointers and in its arrays.
+    pub fn walk(&self) -> Types {
+        Types { stack: vec![self] }
+    }
+
+    /// Name of the declaration this type contains by value, looking through arrays only.
+    fn embedded_name(&self) -> Option<&str> {
+        match *self {
+            EfiType::Id(ref name) => Some(name),
+            EfiType::Array(ref ty, _) => ty.embedded_name(),
+            _ => None,
+        }
+    }
+
+    /// Name of the declaration this type refers to, looking through pointers and arrays.
+    fn referenced_name(&self) -> Option<&str> {
+        match *self {
+            EfiType::Id(ref name) => Some(name),
+            EfiType::Ptr(ref ty) |
+            EfiType::ConstPtr(ref ty) |
+            EfiType::Array(ref ty, _) => ty.referenced_name(),
+            _ => None,
+        }
+    }
+}
+
+/// Iterator over types and the types nested in them, see `EfiType::walk` and
+/// `EfiModule::iter_types`.
+pub struct Types<'a> {
+    stack: Vec<&'a EfiType>,
+}
+
+impl<'a> Iterator for Types<'a> {
+    type Item = &'a EfiType;
+
+    fn next(&mut self) -> Option<&'a EfiType> {
+        let ty = match self.stack.pop() {
+            Some(ty) => ty,
+            None => return None,
+        };
+        match *ty {
+            EfiType::Ptr(ref inner) |
+            EfiType::ConstPtr(ref inner) |
+            EfiType::Array(ref inner, _) => self.stack.push(inner),
+            _ => {}
+        }
+        Some(ty)
+    }
+}
+
+/// C-like spelling for diagnostics, builtin types use the names of the template's markers, e.g.
+/// `efi_uint32[4]`.
+impl fmt::Display for EfiType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let is_ptr = |ty: &EfiType| match *ty {
+            EfiType::Ptr(_) |
+            EfiType::ConstPtr(_) |
+            EfiType::Ucs2Ptr => true,
+            _ => false,
+        };
+
+        match *self {
+            EfiType::Void => write!(f, "void"),
+            EfiType::Status => write!(f, "efi_status"),
+            EfiType::Bool => write!(f, "efi_bool"),
+            EfiType::Int8 => write!(f, "efi_int8"),
+            EfiType::UInt8 => write!(f, "efi_uint8"),
+            EfiType::Int16 => write!(f, "efi_int16"),
+            EfiType::UInt16 => write!(f, "efi_uint16"),
+            EfiType::Int32 => write!(f, "efi_int32"),
+            EfiType::UInt32 => write!(f, "efi_uint32"),
+            EfiType::Int64 => write!(f, "efi_int64"),
+            EfiType::UInt64 => write!(f, "efi_uint64"),
+            EfiType::IntN => write!(f, "efi_intn"),
+            EfiType::UIntN => write!(f, "efi_uintn"),
+            EfiType::Char8 => write!(f, "efi_char8"),
+            EfiType::Char16 => write!(f, "efi_char16"),
+            EfiType::Float32 => write!(f, "float"),
+            EfiType::Float64 => write!(f, "double"),
+            EfiType::Ucs2Ptr => write!(f, "EFI_STRING"),
+            EfiType::StringId => write!(f, "EFI_STRING_ID"),
+            EfiType::Guid => write!(f, "efi_guid"),
+            EfiType::Id(ref name) => write!(f, "{}", name),
+            EfiType::Ptr(ref ty) if is_ptr(ty) => write!(f, "{}*", ty),
+            EfiType::Ptr(ref ty) => write!(f, "{} *", ty),
+            EfiType::ConstPtr(ref ty) if is_ptr(ty) => write!(f, "{}const *", ty),
+            EfiType::ConstPtr(ref ty) => write!(f, "const {} *", ty),
+            EfiType::Array(ref ty, len) => write!(f, "{}[{}]", ty, len),
+        }
+    }
+}
+
+impl EfiGuid {
+    /// The GUID as laid out in memory, the first three fields are little-endian.
+    pub fn to_bytes(&self) -> [u8; 16] {
+        let mut bytes = [0; 16];
+        for i in 0..4 {
+            bytes[i] = (self.data1 >> (8 * i)) as u8;
+        }
+        for i in 0..2 {
+            bytes[4 + i] = (self.data2 >> (8 * i)) as u8;
+            bytes[6 + i] = (self.data3 >> (8 * i)) as u8;
+        }
+        bytes[8..].copy_from_slice(&self.data4);
+        bytes
+    }
+}
+
+/// Registry format, e.g. `964e5b21-6459-11d2-8e39-00a0c969723b`.
+impl fmt::Display for EfiGuid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(write!(f, "{:08x}-{:04x}-{:04x}-", self.data1, self.data2, self.data3));
+        for (i, b) in self.data4.iter().enumerate() {
+            try!(write!(f, "{}{:02x}", if i == 2 { "-" } else { "" }, b));
+        }
+        Ok(())
+    }
+}
+
+impl EfiRecord {
+    fn compute_layout(&self,
+                      module: &EfiModule,
+                      pointer_width: u8)
+                      -> (Vec<(String, usize)>, Option<(usize, usize)>) {
+        let mut offsets = Vec::with_capacity(self.fields.len());
+        let mut size = 0;
+        let mut align = 1;
+        // storage unit of the preceding bitfield as offset, size and bits used, MS layout only
+        let mut unit: Option<(usize, usize, usize)> = None;
+        // bit position following the preceding bitfield, SysV layout only
+        let mut bits: Option<usize> = None;
+
+        for field in &self.fields {
+            let (fsize, falign) = match field.ty.layout(module, pointer_width) {
+                Some(l) => l,
+                None => return (offsets, None),
+            };
+
+            let width = field.bit_width.map(|w| w as usize);
+
+            // SysV bitfields continue at the next free bit unless they would straddle a
+            // boundary of their type's alignment, whatever the type of the preceding field
+            let sysv_bitfield = self.kind == EfiRecordKind::Struct && !self.ms_layout;
+            if let (Some(width), true) = (width, sysv_bitfield) {
+                let unit_bits = falign * 8;
+                let mut start = bits.unwrap_or(size * 8);
+                if width == 0 || start / unit_bits != (start + width - 1) / unit_bits {
+                    start = align_to(start, unit_bits);
+                }
+                bits = Some(start + width);
+
+                offsets.push((field.name.clone(), start / unit_bits * falign));
+                size = max(size, (start + width + 7) / 8);
+                align = max(align, falign);
+                continue;
+            }
+            bits = None;
+
+            // MS bitfields share a unit of their type while they fit, a type change starts a
+            // new one
+            let offset = match (unit, width) {
+                (Some((offset, unit_size, used)), Some(width)) if unit_size == fsize &&
+                                                                  used + width <= fsize * 8 => {
+                    unit = Some((offset, unit_size, used + width));
+                    offset
+                }
+                _ => {
+                    let offset = match self.kind {
+                        EfiRecordKind::Struct => align_to(size, falign),
+                        EfiRecordKind::Union => 0,
+                    };
+                    unit = width.map(|width| (offset, fsize, width));
+                    offset
+                }
+            };
+
+            offsets.push((field.name.clone(), offset));
+            size = max(size, offset + fsize);
+            align = max(align, falign);
+        }
+
+        (offsets, Some((align_to(size, align), align)))
+    }
+
+    /// Size and alignment in bytes, see `EfiType::layout`.
+    pub fn layout(&self, module: &EfiModule, pointer_width: u8) -> Option<(usize, usize)> {
+        self.compute_layout(module, pointer_width).1
+    }
+
+    /// Byte offset of every field, following the C layout rules for a target with
+    /// `pointer_width` bit pointers. Bitfields report the offset of their storage unit.
+    ///
+    /// Stops at the first field whose layout cannot be determined from `module`.
+    pub fn field_offsets(&self, module: &EfiModule, pointer_width: u8) -> Vec<(String, usize)> {
+        self.compute_layout(module, pointer_width).0
+    }
+}
+
+impl EfiMethod {
+    /// Whether a call through one method's signature is valid for the other on a target with
+    /// `pointer_width` bit pointers. Argument names, directions and optionality don't matter.
+    pub fn abi_compatible(&self, other: &EfiMethod, pointer_width: u8) -> bool {
+        self.abi == other.abi && self.ty.abi_eq(&other.ty, pointer_width) &&
+        self.args.len() == other.args.len() &&
+        self.args.iter().zip(&other.args).all(|(a, b)| a.ty.abi_eq(&b.ty, pointer_width))
+    }
+}
+
+/// The prototype as `ret name(args)`, with the `IN`, `OUT` and `OPTIONAL` markers.
+impl fmt::Display for EfiMethod {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(write!(f, "{} {}(", self.ty, self.name));
+        for (i, arg) in self.args.iter().enumerate() {
+            if i > 0 {
+                try!(write!(f, ", "));
+            }
+            let dir = match arg.dir {
+                EfiArgDir::In => "IN",
+                EfiArgDir::Out => "OUT",
+                EfiArgDir::InOut => "IN OUT",
+            };
+            let ty = arg.ty.to_string();
+            let sep = if ty.ends_with('*') || arg.name.is_empty() { "" } else { " " };
+            try!(write!(f, "{} {}{}{}", dir, ty, sep, arg.name));
+            if arg.optional {
+                try!(write!(f, " OPTIONAL"));
+            }
+        }
+        write!(f, ")")
+    }
+}
+
+impl EfiProtocol {
+    /// Names of all declarations used by the methods and fields, looking through pointers and
+    /// arrays.
+    pub fn referenced_types(&self) -> HashSet<String> {
+        let methods = self.methods
+            .iter()
+            .flat_map(|m| Some(&m.ty).into_iter().chain(m.args.iter().map(|a| &a.ty)));
+        let fields = self.fields.iter().map(|f| &f.ty);
+        methods.chain(fields).filter_map(|ty| ty.referenced_name()).map(String::from).collect()
+    }
+}
+
+impl EfiModule {
+    /// Every type used anywhere in the module, i.e. by fields, by method results and arguments
+    /// and by aliases, including the types behind pointers and in arrays. Types used more than once
+    /// are yielded every time.
+    pub fn iter_types(&self) -> Types {
+        let methods = self.protocols
+            .iter()
+            .flat_map(|p| p.methods.iter())
+            .chain(self.functions.iter())
+            .chain(self.callbacks.iter())
+            .flat_map(|m| Some(&m.ty).into_iter().chain(m.args.iter().map(|a| &a.ty)));
+        let fields = self.protocols
+            .iter()
+            .flat_map(|p| p.fields.iter())
+            .chain(self.records.iter().flat_map(|r| r.fields.iter()))
+            .map(|f| &f.ty);
+        let aliases = self.aliases.iter().map(|a| &a.1);
+
+        // the stack is popped from the back
+        let mut stack: Vec<&EfiType> = methods.chain(fields).chain(aliases).collect();
+        stack.reverse();
+        Types { stack: stack }
+    }
+
+    /// The records ordered such that every record comes after the ones it embeds by value,
+    /// otherwise in module order. Records that are only pointed to may come later.
+    ///
+    /// Fails if records embed each other by value, naming the cycle.
+    pub fn sorted_records(&self) -> Result<Vec<&EfiRecord>, ParseError> {
+        fn visit<'a>(record: &'a EfiRecord,
+                     records: &HashMap<&str, &'a EfiRecord>,
+                     path: &mut Vec<&'a str>,
+                     done: &mut HashSet<&'a str>,
+                     sorted: &mut Vec<&'a EfiRecord>)
+                     -> Result<(), ParseError> {
+            if done.contains(record.name.as_str()) {
+                return Ok(());
+            }
+            if let Some(i) = path.iter().position(|&n| n == record.name) {
+                let mut cycle = path[i..].to_vec();
+                cycle.push(&record.name);
+                return Err(ParseError::Malformed(format!("records embed each other: {}",
+                                                         cycle.join(" -> "))));
+            }
+
+            path.push(&record.name);
+            for field in &record.fields {
+                if let Some(dep) = field.ty.embedded_name().and_then(|n| records.get(n)) {
+                    try!(visit(dep, records, path, done, sorted));
+                }
+            }
+            path.pop();
+
+            done.insert(&record.name);
+            sorted.push(record);
+            Ok(())
+        }
+
+        let records = self.records.iter().map(|r| (r.name.as_str(), r)).collect();
+        let mut done = HashSet::new();
+        let mut sorted = Vec::new();
+        for record in &self.records {
+            try!(visit(record, &records, &mut Vec::new(), &mut done, &mut sorted));
+        }
+        Ok(sorted)
+    }
+
+    /// The distinct types of all record fields.
+    pub fn field_types(&self) -> HashSet<EfiType> {
+        self.records
+            .iter()
+            .flat_map(|r| r.fields.iter())
+            .map(|f| f.ty.clone())
+            .collect()
+    }
+
+    /// A module path for every protocol, record and enum, e.g. `["block_io"]` for
+    /// `EFI_BLOCK_IO_PROTOCOL`.
+    pub fn to_module_paths(&self) -> HashMap<String, Vec<String>> {
+        let module_name = |name: &str| {
+            name.split('_')
+                .filter(|s| !s.is_empty() && *s != "EFI" && *s != "PROTOCOL")
+                .map(|s| s.to_lowercase())
+                .collect::<Vec<_>>()
+                .join("_")
+        };
+
+        let protocols = self.protocols.iter().map(|p| &p.name);
+        let records = self.records.iter().map(|r| &r.name);
+        let enums = self.enums.iter().map(|e| &e.name);
+        protocols.chain(records)
+            .chain(enums)
+            .map(|name| (name.clone(), vec![module_name(name)]))
+            .collect()
+    }
+
+    /// All GUIDs defined in the module by name. Protocol GUIDs are included, since they are
+    /// taken from these constants as well.
+    pub fn all_guids(&self) -> Vec<(String, [u8; 16])> {
+        self.constants
+            .iter()
+            .filter_map(|c| match c.value {
+                EfiConstValue::Guid(ref guid) => Some((c.name.clone(), guid.to_bytes())),
+                EfiConstValue::Int(_) => None,
+            })
+            .collect()
+    }
+
+    /// A line for every item and member, e.g. `record EFI_FOO.Bar: efi_uint32 *`, for comparing
+    /// parser output in snapshot tests.
+    ///
+    /// Items are sorted by kind and name, members stay in declaration order.
+    pub fn golden_string(&self) -> String {
+        let mut items: Vec<Vec<String>> = Vec::new();
+
+        for proto in &self.protocols {
+            let mut lines = vec![match proto.guid {
+                Some(ref guid) => format!("protocol {} {}", proto.name, guid),
+                None if proto.opaque => format!("protocol {} opaque", proto.name),
+                None => format!("protocol {}", proto.name),
+            }];
+            lines.extend(proto.methods
+                .iter()
+                .map(|m| format!("protocol {}.{}: {}", proto.name, m.name, m)));
+            lines.extend(proto.fields
+                .iter()
+                .map(|f| format!("protocol {}.{}: {}", proto.name, f.name, f.ty)));
+            items.push(lines);
+        }
+        for record in &self.records {
+            let kind = match record.kind {
+                EfiRecordKind::Struct => "struct",
+                EfiRecordKind::Union => "union",
+            };
+            let mut lines = vec![format!("record {} {}", record.name, kind)];
+            lines.extend(record.fields.iter().map(|f| match f.bit_width {
+                Some(width) => format!("record {}.{}: {} : {}", record.name, f.name, f.ty, width),
+                None => format!("record {}.{}: {}", record.name, f.name, f.ty),
+            }));
+            items.push(lines);
+        }
+        for enm in &self.enums {
+            let mut lines = vec![format!("enum {}", enm.name)];
+            lines.extend(enm.fields.iter().map(|v| match v.value {
+                Some(value) => format!("enum {}.{} = {}", enm.name, v.name, value),
+                None => format!("enum {}.{}", enm.name, v.name),
+            }));
+            items.push(lines);
+        }
+        for func in &self.functions {
+            items.push(vec![format!("function {}", func)]);
+        }
+        for callback in &self.callbacks {
+            items.push(vec![format!("callback {}", callback)]);
+        }
+        for &(ref name, ref ty) in &self.aliases {
+            items.push(vec![format!("alias {}: {}", name, ty)]);
+        }
+        for &(ref name, rev) in &self.revisions {
+            items.push(vec![format!("revision {} = {:#x}", name, rev)]);
+        }
+        for constant in &self.constants {
+            items.push(vec![match constant.value {
+                EfiConstValue::Int(value) => format!("constant {} = {:#x}", constant.name, value),
+                EfiConstValue::Guid(ref guid) => format!("constant {} = {}", constant.name, guid),
+            }]);
+        }
+        for &(ref expr, ref msg) in &self.static_asserts {
+            items.push(vec![format!("static_assert {}: {}", expr, msg)]);
+        }
+        for (name, path) in &self.extern_types {
+            items.push(vec![format!("extern {} = {}", name, path)]);
+        }
+
+        items.sort();
+        let mut golden = String::new();
+        for line in items.iter().flat_map(|lines| lines.iter()) {
+            golden.push_str(line);
+            golden.push('\n');
+        }
+        golden
+    }
+
+    /// The protocol `name` together with all declarations it transitively refers to.
+    ///
+    /// Returns `None` if the module does not define protocol `name`.
+    pub fn closure(&self, name: &str) -> Option<EfiModule> {
+        if !self.protocols.iter().any(|p| p.name == name) {
+            return None;
+        }
+
+        let mut seen = HashSet::new();
+        let mut pending = vec![String::from(name)];
+        while let Some(name) = pending.pop() {
+            if seen.contains(&name) {
+                continue;
+            }
+
+            if let Some(proto) = self.protocols.iter().find(|p| p.name == name) {
+                pending.extend(proto.referenced_types());
+                // the generated fields name the typedef a method is declared with
+                pending.extend(proto.methods.iter().filter_map(|m| m.typedef.clone()));
+            } else if let Some(record) = self.records.iter().find(|r| r.name == name) {
+                pending.extend(record.fields
+                    .iter()
+                    .filter_map(|f| f.ty.referenced_name())
+                    .map(String::from));
+            } else if let Some(callback) = self.callbacks.iter().find(|c| c.name == name) {
+                pending.extend(Some(&callback.ty)
+                    .into_iter()
+                    .chain(callback.args.iter().map(|a| &a.ty))
+                    .filter_map(|ty| ty.referenced_name())
+                    .map(String::from));
+            } else if let Some(alias) = self.aliases.iter().find(|a| a.0 == name) {
+                pending.extend(alias.1.referenced_name().map(String::from));
+            }
+            seen.insert(name);
+        }
+
+        Some(EfiModule {
+            protocols: self.protocols.iter().filter(|p| seen.contains(&p.name)).cloned().collect(),
+            records: self.records.iter().filter(|r| seen.contains(&r.name)).cloned().collect(),
+            enums: self.enums.iter().filter(|e| seen.contains(&e.name)).cloned().collect(),
+            functions: Vec::new(),
+            callbacks: self.callbacks.iter().filter(|c| seen.contains(&c.name)).cloned().collect(),
+            aliases: self.aliases.iter().filter(|a| seen.contains(&a.0)).cloned().collect(),
+            revisions: self.revisions.clone(),
+            constants: self.constants.clone(),
+            static_asserts: Vec::new(),
+            extern_types: self.extern_types.clone(),
+            warnings: Vec::new(),
+        })
+    }
+
+    /// Adds the items of `other` that this module does not have yet. Headers commonly include
+    /// each other, so most duplicates are the same declaration seen twice; a record that is only
+    /// forward declared here is replaced by its definition.
+    pub fn merge(&mut self, other: EfiModule) {
+        for proto in other.protocols {
+            match self.protocols.iter().position(|p| p.name == proto.name) {
+                Some(i) => {
+                    if self.protocols[i].opaque {
+                        self.protocols[i] = proto;
+                    }
+                }
+                None => self.protocols.push(proto),
+            }
+        }
+        for record in other.records {
+            match self.records.iter().position(|r| r.name == record.name) {
+                Some(i) => {
+                    if self.records[i].fields.is_empty() {
+                        self.records[i] = record;
+                    }
+                }
+                None => self.records.push(record),
+            }
+        }
+        for enm in other.enums {
+            if !self.enums.iter().any(|e| e.name == enm.name) {
+                self.enums.push(enm);
+            }
+        }
+        for func in other.functions {
+            if !self.functions.iter().any(|f| f.name == func.name) {
+                self.functions.push(func);
+            }
+        }
+        for callback in other.callbacks {
+            if !self.callbacks.iter().any(|c| c.name == callback.name) {
+                self.callbacks.push(callback);
+            }
+        }
+        for alias in other.aliases {
+            if !self.aliases.iter().any(|a| a.0 == alias.0) {
+                self.aliases.push(alias);
+            }
+        }
+        for rev in other.revisions {
+            if !self.revisions.iter().any(|r| r.0 == rev.0) {
+                self.revisions.push(rev);
+            }
+        }
+        for constant in other.constants {
+            if !self.constants.iter().any(|c| c.name == constant.name) {
+                self.constants.push(constant);
+            }
+        }
+        for assert in other.static_asserts {
+            if !self.static_asserts.contains(&assert) {
+                self.static_asserts.push(assert);
+            }
+        }
+        for (name, path) in other.extern_types {
+            self.extern_types.entry(name).or_insert(path);
+        }
+        for warning in other.warnings {
+            if !self.warnings.contains(&warning) {
+                self.warnings.push(warning);
+            }
+        }
+    }
+
+    /// Replaces aliases used exactly once by the type they stand for at the use, and drops them.
+    /// Aliases used more than once, or not at all, are kept.
+    pub fn inline_single_use_aliases(&mut self) {
+        struct Inline(HashMap<String, EfiType>);
+
+        impl EfiVisitor for Inline {
+            fn visit_type(&mut self, ty: &mut EfiType) {
+                let inlined = match *ty {
+                    EfiType::Id(ref name) => self.0.get(name).cloned(),
+                    _ => None,
+                };
+                if let Some(inlined) = inlined {
+                    *ty = inlined;
+                }
+            }
+        }
+
+        let single_use: HashMap<_, _> = {
+            let mut uses = HashMap::new();
+            for ty in self.iter_types() {
+                if let EfiType::Id(ref name) = *ty {
+                    *uses.entry(name.as_str()).or_insert(0) += 1;
+                }
+            }
+            self.aliases.iter().filter(|a| uses.get(a.0.as_str()) == Some(&1)).cloned().collect()
+        };
+
+        self.aliases.retain(|a| !single_use.contains_key(&a.0));
+        walk_mut(self, &mut Inline(single_use));
+    }
+
+    /// Merges anonymous records with the same members into the first of them, pointing the
+    /// references to the others at it.
+    pub fn merge_anonymous_records(&mut self) {
+        struct Rename(HashMap<String, String>);
+
+        impl EfiVisitor for Rename {
+            fn visit_type(&mut self, ty: &mut EfiType) {
+                let renamed = match *ty {
+                    EfiType::Id(ref name) => self.0.get(name).cloned(),
+                    _ => None,
+                };
+                if let Some(name) = renamed {
+                    *ty = EfiType::Id(name);
+                }
+            }
+        }
+
+        let same = |a: &EfiRecord, b: &EfiRecord| {
+            a.kind == b.kind && a.ms_layout == b.ms_layout && a.fields.len() == b.fields.len() &&
+            a.fields.iter().zip(&b.fields).all(|(f, g)| {
+                f.name == g.name && f.ty == g.ty && f.bit_width == g.bit_width
+            })
+        };
+
+        // records nested in merged ones may only turn out the same once those are renamed
+        loop {
+            let mut renames = HashMap::new();
+            for (i, record) in self.records.iter().enumerate().filter(|&(_, r)| r.anonymous) {
+                if let Some(first) = self.records[..i]
+                    .iter()
+                    .find(|r| r.anonymous && same(r, record)) {
+                    renames.insert(record.name.clone(), first.name.clone());
+                }
+            }
+            if renames.is_empty() {
+                break;
+            }
+
+            self.records.retain(|r| !renames.contains_key(&r.name));
+            walk_mut(self, &mut Rename(renames));
+        }
+    }
+
+    /// Fills in missing documentation from `docs`, e.g. comments collected from a vendor header.
+    ///
+    /// Records, functions and callbacks are looked up by their name, fields and protocol methods
+    /// by the name qualified with their parent's, e.g. `EFI_FOO_PROTOCOL.Reset`. Existing
+    /// documentation is kept.
+    pub fn apply_docs(&mut self, docs: &HashMap<String, String>) {
+        fn fill(doc: &mut Option<String>, docs: &HashMap<String, String>, name: &str) {
+            if doc.is_none() {
+                *doc = docs.get(name).cloned();
+            }
+        }
+
+        for proto in &mut self.protocols {
+            for method in &mut proto.methods {
+                fill(&mut method.doc, docs, &format!("{}.{}", proto.name, method.name));
+            }
+            for field in &mut proto.fields {
+                fill(&mut field.doc, docs, &format!("{}.{}", proto.name, field.name));
+            }
+        }
+        for record in &mut self.records {
+            fill(&mut record.doc, docs, &record.name);
+            for field in &mut record.fields {
+                fill(&mut field.doc, docs, &format!("{}.{}", record.name, field.name));
+            }
+        }
+        for method in self.functions.iter_mut().chain(self.callbacks.iter_mut()) {
+            fill(&mut method.doc, docs, &method.name);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn field(name: &str, ty: EfiType) -> EfiField {
+        EfiField {
+            name: String::from(name),
+            ty: ty,
+            is_reserved: false,
+            bit_width: None,
+            len_const: None,
+            doc: None,
+        }
+    }
+
+    fn record(name: &str, fields: Vec<EfiField>) -> EfiRecord {
+        EfiRecord {
+            name: String::from(name),
+            fields: fields,
+            kind: EfiRecordKind::Struct,
+            doc: None,
+            ms_layout: false,
+            anonymous: false,
+        }
+    }
+
+    #[test]
+    fn field_offsets_follow_alignment() {
+        let module = EfiModule::default();
+        let foo = record("EFI_FOO",
+                         vec![field("Small", EfiType::UInt8), field("Large", EfiType::UInt32)]);
+        assert_eq!(foo.field_offsets(&module, 64),
+                   vec![(String::from("Small"), 0), (String::from("Large"), 4)]);
+        assert_eq!(foo.layout(&module, 64), Some((8, 4)));
+
+        let bar = EfiRecord { kind: EfiRecordKind::Union, ..foo.clone() };
+        assert_eq!(bar.field_offsets(&module, 64),
+                   vec![(String::from("Small"), 0), (String::from("Large"), 0)]);
+        assert_eq!(bar.layout(&module, 64), Some((4, 4)));
+    }
+
+    #[test]
+    fn ms_struct_bitfields_follow_the_ms_rules() {
+        let module = EfiModule::default();
+        let bitfield = |name: &str, ty, width| {
+            EfiField { bit_width: Some(width), ..field(name, ty) }
+        };
+        let sysv = record("EFI_MODE",
+                          vec![bitfield("A", EfiType::UInt8, 3),
+                               bitfield("B", EfiType::UInt32, 5),
+                               bitfield("C", EfiType::UInt32, 30),
+                               field("D", EfiType::UInt8)]);
+        let ms = EfiRecord { ms_layout: true, ..sysv.clone() };
+        let offsets = |r: &EfiRecord| {
+            r.field_offsets(&module, 64).into_iter().map(|(_, o)| o).collect::<Vec<_>>()
+        };
+
+        // what MSVC makes of it: B can't share the unit of A, C doesn't fit the one of B
+        assert_eq!(offsets(&ms), vec![0, 4, 8, 12]);
+        assert_eq!(ms.layout(&module, 64), Some((16, 4)));
+        // GCC packs A and B into the first UINT32 and puts D right after C
+        assert_eq!(offsets(&sysv), vec![0, 0, 4, 8]);
+        assert_eq!(sysv.layout(&module, 64), Some((12, 4)));
+    }
+
+    #[test]
+    fn field_offsets_stop_at_unknown_types() {
+        let module = EfiModule::default();
+        let foo = record("EFI_FOO",
+                         vec![field("Known", EfiType::UIntN),
+                              field("Unknown", EfiType::Id(String::from("EFI_BAR"))),
+                              field("After", EfiType::UInt8)]);
+        assert_eq!(foo.field_offsets(&module, 32), vec![(String::from("Known"), 0)]);
+        assert_eq!(foo.layout(&module, 32), None);
+    }
+
+    #[test]
+    fn native_integers_match_their_width() {
+        assert!(EfiType::UIntN.abi_eq(&EfiType::UInt64, 64));
+        assert!(!EfiType::UIntN.abi_eq(&EfiType::UInt64, 32));
+        assert!(EfiType::UIntN.abi_eq(&EfiType::UInt32, 32));
+        assert!(!EfiType::IntN.abi_eq(&EfiType::UInt64, 64));
+
+        let ptr = |ty| EfiType::Ptr(Box::new(ty));
+        assert!(ptr(EfiType::UIntN).abi_eq(&EfiType::ConstPtr(Box::new(EfiType::UInt64)), 64));
+        assert!(EfiType::Ucs2Ptr.abi_eq(&ptr(EfiType::Char16), 64));
+        assert!(!EfiType::Array(Box::new(EfiType::UIntN), 2)
+            .abi_eq(&EfiType::Array(Box::new(EfiType::UInt64), 3), 64));
+    }
+
+    #[test]
+    fn concrete_width_lowers_nested_native_integers() {
+        let ty = EfiType::Array(Box::new(EfiType::Ptr(Box::new(EfiType::IntN))), 4);
+        assert_eq!(ty.concrete_width(32),
+                   EfiType::Array(Box::new(EfiType::Ptr(Box::new(EfiType::Int32))), 4));
+        assert_eq!(EfiType::UIntN.concrete_width(64), EfiType::UInt64);
+        assert_eq!(EfiType::UIntN.concrete_width(16), EfiType::UIntN);
+        assert_eq!(EfiType::UInt8.concrete_width(64), EfiType::UInt8);
+    }
+
+    fn method(ty: EfiType, args: Vec<EfiType>) -> EfiMethod {
+        EfiMethod {
+            name: String::from("Foo"),
+            ty: ty,
+            args: args.into_iter()
+                .map(|ty| {
+                    EfiArg {
+                        name: String::new(),
+                        ty: ty,
+                        dir: EfiArgDir::In,
+                        optional: false,
+                        restrict: false,
+                        length_of: None,
+                    }
+                })
+                .collect(),
+            abi: EfiAbi::Efiapi,
+            deprecated: false,
+            doc: None,
+            typedef: None,
+            const_ptr: false,
+            slices: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn abi_compatible_methods() {
+        let a = method(EfiType::Status, vec![EfiType::UIntN, EfiType::Ucs2Ptr]);
+        let mut b = method(EfiType::Status,
+                           vec![EfiType::UInt64, EfiType::ConstPtr(Box::new(EfiType::Char16))]);
+        b.name = String::from("Bar");
+        b.args[0].dir = EfiArgDir::Out;
+        assert!(a.abi_compatible(&b, 64));
+        assert!(!a.abi_compatible(&b, 32));
+
+        assert!(!a.abi_compatible(&method(EfiType::Status, vec![EfiType::UIntN]), 64));
+        let void = method(EfiType::Void, vec![EfiType::UIntN, EfiType::Ucs2Ptr]);
+        assert!(!a.abi_compatible(&void, 64));
+        let mut c = a.clone();
+        c.abi = EfiAbi::C;
+        assert!(!a.abi_compatible(&c, 64));
+    }
+
+    #[test]
+    fn display_types() {
+        let ptr = |ty| EfiType::Ptr(Box::new(ty));
+        let const_ptr = |ty| EfiType::ConstPtr(Box::new(ty));
+        assert_eq!(EfiType::UInt32.to_string(), "efi_uint32");
+        assert_eq!(EfiType::Array(Box::new(EfiType::UInt8), 4).to_string(), "efi_uint8[4]");
+        assert_eq!(ptr(EfiType::Id(String::from("EFI_FOO"))).to_string(), "EFI_FOO *");
+        assert_eq!(ptr(ptr(EfiType::Void)).to_string(), "void **");
+        assert_eq!(const_ptr(EfiType::Char8).to_string(), "const efi_char8 *");
+        assert_eq!(const_ptr(ptr(EfiType::Void)).to_string(), "void *const *");
+        assert_eq!(ptr(EfiType::Ucs2Ptr).to_string(), "EFI_STRING*");
+    }
+
+    #[test]
+    fn display_methods() {
+        let mut reset = method(EfiType::Status,
+                               vec![EfiType::Ptr(Box::new(EfiType::Id(String::from("EFI_FOO")))),
+                                    EfiType::Bool,
+                                    EfiType::Ptr(Box::new(EfiType::UIntN))]);
+        reset.name = String::from("Reset");
+        reset.args[0].name = String::from("This");
+        reset.args[1].optional = true;
+        reset.args[2].name = String::from("Size");
+        reset.args[2].dir = EfiArgDir::InOut;
+        assert_eq!(reset.to_string(),
+                   "efi_status Reset(IN EFI_FOO *This, IN efi_bool OPTIONAL, \
+                    IN OUT efi_uintn *Size)");
+    }
+
+    #[test]
+    fn iter_types_includes_nested_types() {
+        let bytes = EfiType::Ptr(Box::new(EfiType::Array(Box::new(EfiType::UInt8), 2)));
+        let module = EfiModule {
+            records: vec![record("EFI_FOO", vec![field("Bar", bytes)])],
+            callbacks: vec![method(EfiType::Status, vec![EfiType::UIntN])],
+            aliases: vec![(String::from("EFI_TPL"), EfiType::UIntN)],
+            ..EfiModule::default()
+        };
+        let types: Vec<_> = module.iter_types().map(|ty| ty.to_string()).collect();
+        assert_eq!(types,
+                   vec!["efi_status", "efi_uintn", "efi_uint8[2] *", "efi_uint8[2]", "efi_uint8",
+                        "efi_uintn"]);
+    }
+
+    #[test]
+    fn sorted_records_put_embedded_records_first() {
+        let id = |name: &str| EfiType::Id(String::from(name));
+        let next = EfiType::Ptr(Box::new(id("EFI_LIST")));
+        let module = EfiModule {
+            records: vec![record("EFI_OUTER", vec![field("Inner", id("EFI_INNER"))]),
+                          record("EFI_LIST", vec![field("Next", next)]),
+                          record("EFI_INNER",
+                                 vec![field("Items", EfiType::Array(Box::new(id("EFI_ITEM")), 2))]),
+                          record("EFI_ITEM", vec![field("Value", EfiType::UInt8)])],
+            ..EfiModule::default()
+        };
+        let names: Vec<_> = module.sorted_records()
+            .unwrap()
+            .iter()
+            .map(|r| r.name.as_str())
+            .collect();
+        assert_eq!(names, vec!["EFI_ITEM", "EFI_INNER", "EFI_OUTER", "EFI_LIST"]);
+
+        let cyclic = EfiModule {
+            records: vec![record("EFI_A", vec![field("B", id("EFI_B"))]),
+                          record("EFI_B", vec![field("A", id("EFI_A"))])],
+            ..EfiModule::default()
+        };
+        match cyclic.sorted_records() {
+            Err(ParseError::Malformed(msg)) => {
+                assert_eq!(msg, "records embed each other: EFI_A -> EFI_B -> EFI_A")
+            }
+            result => panic!("expected a cycle, got {:?}", result.map(|r| r.len())),
+        }
+    }
+
+    #[test]
+    fn module_paths_drop_the_prefix_and_suffix() {
+        let module = EfiModule {
+            protocols: vec![EfiProtocol {
+                                name: String::from("EFI_BLOCK_IO_PROTOCOL"),
+                                methods: Vec::new(),
+                                fields: Vec::new(),
+                                deprecated: false,
+                                guid: None,
+                                opaque: true,
+                            }],
+            records: vec![record("EFI_BLOCK_IO_MEDIA", Vec::new())],
+            ..EfiModule::default()
+        };
+        let paths = module.to_module_paths();
+        assert_eq!(paths.len(), 2);
+        assert_eq!(paths["EFI_BLOCK_IO_PROTOCOL"], vec!["block_io"]);
+        assert_eq!(paths["EFI_BLOCK_IO_MEDIA"], vec!["block_io_media"]);
+    }
+
+    #[test]
+    fn golden_string_sorts_items() {
+        let mut bits = field("Mode", EfiType::UInt32);
+        bits.bit_width = Some(4);
+        let next = field("Next", EfiType::Ptr(Box::new(EfiType::Void)));
+        let module = EfiModule {
+            records: vec![record("EFI_ZED", vec![next]),
+                          record("EFI_ALPHA", vec![field("Size", EfiType::UIntN), bits])],
+            enums: vec![EfiEnum {
+                            name: String::from("EFI_KIND"),
+                            fields: vec![EfiVariant {
+                                             name: String::from("EfiKindA"),
+                                             value: Some(1),
+                                         },
+                                         EfiVariant {
+                                             name: String::from("EfiKindB"),
+                                             value: None,
+                                         }],
+                            signed: false,
+                        }],
+            aliases: vec![(String::from("EFI_TPL"), EfiType::UIntN)],
+            constants: vec![EfiConstant {
+                                name: String::from("EFI_FOO_REVISION"),
+                                value: EfiConstValue::Int(0x10000),
+                            }],
+            ..EfiModule::default()
+        };
+        assert_eq!(module.golden_string(),
+                   "alias EFI_TPL: efi_uintn\n\
+                    constant EFI_FOO_REVISION = 0x10000\n\
+                    enum EFI_KIND\n\
+                    enum EFI_KIND.EfiKindA = 1\n\
+                    enum EFI_KIND.EfiKindB\n\
+                    record EFI_ALPHA struct\n\
+                    record EFI_ALPHA.Size: efi_uintn\n\
+                    record EFI_ALPHA.Mode: efi_uint32 : 4\n\
+                    record EFI_ZED struct\n\
+                    record EFI_ZED.Next: void *\n");
+    }
+
+    #[test]
+    fn closure_keeps_callbacks_and_aliases() {
+        let id = |name: &str| EfiType::Id(String::from(name));
+        let named = |name: &str, method: EfiMethod| {
+            EfiMethod { name: String::from(name), ..method }
+        };
+        let module = EfiModule {
+            protocols: vec![EfiProtocol {
+                                name: String::from("EFI_FOO_PROTOCOL"),
+                                methods: vec![named("Notify",
+                                                    method(EfiType::Status,
+                                                           vec![id("EFI_FOO_NOTIFY")]))],
+                                fields: Vec::new(),
+                                deprecated: false,
+                                guid: None,
+                                opaque: false,
+                            }],
+            records: vec![record("EFI_FOO_DATA", Vec::new()), record("EFI_BAR", Vec::new())],
+            callbacks: vec![named("EFI_FOO_NOTIFY",
+                                  method(EfiType::Void, vec![id("EFI_FOO_CONTEXT")]))],
+            aliases: vec![(String::from("EFI_FOO_CONTEXT"),
+                           EfiType::Ptr(Box::new(id("EFI_FOO_DATA")))),
+                          (String::from("EFI_BAR_CONTEXT"), EfiType::UIntN)],
+            ..EfiModule::default()
+        };
+
+        let closure = module.closure("EFI_FOO_PROTOCOL").unwrap();
+        let callbacks: Vec<_> = closure.callbacks.iter().map(|c| &c.name[..]).collect();
+        assert_eq!(callbacks, vec!["EFI_FOO_NOTIFY"]);
+        let aliases: Vec<_> = closure.aliases.iter().map(|a| &a.0[..]).collect();
+        assert_eq!(aliases, vec!["EFI_FOO_CONTEXT"]);
+        let records: Vec<_> = closure.records.iter().map(|r| &r.name[..]).collect();
+        assert_eq!(records, vec!["EFI_FOO_DATA"]);
+    }
+
+    #[test]
+    fn apply_docs_fills_missing_comments() {
+        let mut reset = method(EfiType::Status, Vec::new());
+        reset.name = String::from("Reset");
+        let mut stop = method(EfiType::Status, Vec::new());
+        stop.name = String::from("Stop");
+        stop.doc = Some(String::from("Stops the device."));
+        let mut module = EfiModule {
+            protocols: vec![EfiProtocol {
+                                name: String::from("EFI_FOO_PROTOCOL"),
+                                methods: vec![reset, stop],
+                                fields: Vec::new(),
+                                deprecated: false,
+                                guid: None,
+                                opaque: false,
+                            }],
+            ..EfiModule::default()
+        };
+
+        let docs = vec![("EFI_FOO_PROTOCOL.Reset", "Resets the device."),
+                        ("EFI_FOO_PROTOCOL.Stop", "Halts the device."),
+                        ("Reset", "Resets something else.")]
+            .into_iter()
+            .map(|(k, v)| (String::from(k), String::from(v)))
+            .collect();
+        module.apply_docs(&docs);
+
+        let methods = &module.protocols[0].methods;
+        assert_eq!(methods[0].doc, Some(String::from("Resets the device.")));
+        assert_eq!(methods[1].doc, Some(String::from("Stops the device.")));
+    }
+
+    #[test]
+    fn single_use_aliases_are_inlined() {
+        let id = |name: &str| EfiType::Id(String::from(name));
+        let mut module = EfiModule {
+            records: vec![record("EFI_FOO",
+                                 vec![field("Size", id("EFI_SIZE")),
+                                      field("Low", id("EFI_WORD")),
+                                      field("High", id("EFI_WORD")),
+                                      field("Next", EfiType::Ptr(Box::new(id("EFI_NEXT"))))])],
+            aliases: vec![(String::from("EFI_SIZE"), EfiType::UIntN),
+                          (String::from("EFI_WORD"), EfiType::UInt32),
+                          (String::from("EFI_NEXT"), EfiType::Ptr(Box::new(id("EFI_LINK")))),
+                          (String::from("EFI_LINK"), EfiType::UInt64)],
+            ..EfiModule::default()
+        };
+        module.inline_single_use_aliases();
+
+        let types: Vec<_> = module.records[0].fields.iter().map(|f| f.ty.clone()).collect();
+        let ptr = |ty| EfiType::Ptr(Box::new(ty));
+        assert_eq!(types,
+                   vec![EfiType::UIntN,
+                        id("EFI_WORD"),
+                        id("EFI_WORD"),
+                        ptr(ptr(EfiType::UInt64))]);
+        let aliases: Vec<_> = module.aliases.iter().map(|a| a.0.as_str()).collect();
+        assert_eq!(aliases, vec!["EFI_WORD"]);
+    }
+
+    #[test]
+    fn identical_anonymous_records_are_merged() {
+        let union = |name: &str| {
+            EfiRecord {
+                kind: EfiRecordKind::Union,
+                anonymous: true,
+                ..record(name, vec![field("Byte", EfiType::UInt8), field("Word", EfiType::UInt16)])
+            }
+        };
+        let id = |name: &str| EfiType::Id(String::from(name));
+        let mut module = EfiModule {
+            records: vec![union("EFI_FOO_VALUE"),
+                          record("EFI_FOO", vec![field("Value", id("EFI_FOO_VALUE"))]),
+                          union("EFI_BAR_VALUE"),
+                          record("EFI_BAR", vec![field("Value", id("EFI_BAR_VALUE"))]),
+                          // the same members, but a name of its own
+                          EfiRecord { anonymous: false, ..union("EFI_VALUE") }],
+            ..EfiModule::default()
+        };
+        module.merge_anonymous_records();
+
+        let names: Vec<_> = module.records.iter().map(|r| r.name.as_str()).collect();
+        assert_eq!(names, vec!["EFI_FOO_VALUE", "EFI_FOO", "EFI_BAR", "EFI_VALUE"]);
+        assert_eq!(module.records[2].fields[0].ty, id("EFI_FOO_VALUE"));
+    }
 }
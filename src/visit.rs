@@ -0,0 +1,121 @@
+use types::*;
+
+/// Post-processing hook over a parsed module, see `walk_mut`. Every method defaults to doing
+/// nothing.
+pub trait EfiVisitor {
+    fn visit_protocol(&mut self, _proto: &mut EfiProtocol) {}
+    fn visit_record(&mut self, _record: &mut EfiRecord) {}
+    fn visit_enum(&mut self, _enm: &mut EfiEnum) {}
+    fn visit_method(&mut self, _method: &mut EfiMethod) {}
+    fn visit_field(&mut self, _field: &mut EfiField) {}
+    /// Called for every type, including the ones behind pointers and in arrays.
+    fn visit_type(&mut self, _ty: &mut EfiType) {}
+}
+
+fn walk_type<V: EfiVisitor>(ty: &mut EfiType, visitor: &mut V) {
+    visitor.visit_type(ty);
+    match *ty {
+        EfiType::Ptr(ref mut ty) |
+        EfiType::ConstPtr(ref mut ty) |
+        EfiType::Array(ref mut ty, _) => walk_type(ty, visitor),
+        _ => {}
+    }
+}
+
+fn walk_method<V: EfiVisitor>(method: &mut EfiMethod, visitor: &mut V) {
+    visitor.visit_method(method);
+    walk_type(&mut method.ty, visitor);
+    for arg in &mut method.args {
+        walk_type(&mut arg.ty, visitor);
+    }
+}
+
+fn walk_field<V: EfiVisitor>(field: &mut EfiField, visitor: &mut V) {
+    visitor.visit_field(field);
+    walk_type(&mut field.ty, visitor);
+}
+
+/// Visits every item of `module` and everything nested in it. Items are visited before their
+/// contents, so changes a visitor makes to an item are seen by the nested calls.
+pub fn walk_mut<V: EfiVisitor>(module: &mut EfiModule, visitor: &mut V) {
+    for proto in &mut module.protocols {
+        visitor.visit_protocol(proto);
+        for method in &mut proto.methods {
+            walk_method(method, visitor);
+        }
+        for field in &mut proto.fields {
+            walk_field(field, visitor);
+        }
+    }
+
+    for record in &mut module.records {
+        visitor.visit_record(record);
+        for field in &mut record.fields {
+            walk_field(field, visitor);
+        }
+    }
+
+    for enm in &mut module.enums {
+        visitor.visit_enum(enm);
+    }
+
+    for method in module.functions.iter_mut().chain(module.callbacks.iter_mut()) {
+        walk_method(method, visitor);
+    }
+
+    for alias in &mut module.aliases {
+        walk_type(&mut alias.1, visitor);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Rename;
+
+    impl EfiVisitor for Rename {
+        fn visit_record(&mut self, record: &mut EfiRecord) {
+            if record.name == "EFI_FOO" {
+                record.name = String::from("Foo");
+            }
+        }
+
+        fn visit_type(&mut self, ty: &mut EfiType) {
+            if *ty == EfiType::Id(String::from("EFI_FOO")) {
+                *ty = EfiType::Id(String::from("Foo"));
+            }
+        }
+    }
+
+    #[test]
+    fn visitor_rewrites_nested_types() {
+        let foo = || EfiType::Id(String::from("EFI_FOO"));
+        let mut module = EfiModule {
+            records: vec![EfiRecord {
+                              name: String::from("EFI_FOO"),
+                              fields: vec![EfiField {
+                                               name: String::from("Next"),
+                                               ty: EfiType::Ptr(Box::new(foo())),
+                                               is_reserved: false,
+                                               bit_width: None,
+                                               len_const: None,
+                                               doc: None,
+                                           }],
+                              kind: EfiRecordKind::Struct,
+                              doc: None,
+                              ms_layout: false,
+                              anonymous: false,
+                          }],
+            aliases: vec![(String::from("EFI_FOOS"), EfiType::Array(Box::new(foo()), 2))],
+            ..EfiModule::default()
+        };
+
+        walk_mut(&mut module, &mut Rename);
+
+        let renamed = || EfiType::Id(String::from("Foo"));
+        assert_eq!(module.records[0].name, "Foo");
+        assert_eq!(module.records[0].fields[0].ty, EfiType::Ptr(Box::new(renamed())));
+        assert_eq!(module.aliases[0].1, EfiType::Array(Box::new(renamed()), 2));
+    }
+}